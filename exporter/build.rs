@@ -0,0 +1,29 @@
+use std::{path::Path, process::Command};
+
+/// When set, the commit reported by the exporter, for builds outside of a git checkout
+const GIT_SHA_ENV_VAR: &str = "GIT_SHA";
+
+fn git_sha() -> Option<String> {
+    let output = Command::new("git")
+        .args(["rev-parse", "HEAD"])
+        .output()
+        .ok()?;
+    output
+        .status
+        .success()
+        .then(|| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn main() {
+    println!("cargo:rerun-if-env-changed={}", GIT_SHA_ENV_VAR);
+    for path in ["../.git/HEAD", "../.git/refs/heads"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+    let git_sha = std::env::var(GIT_SHA_ENV_VAR)
+        .ok()
+        .or_else(git_sha)
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=EXPORTER_GIT_SHA={}", git_sha);
+}
@@ -0,0 +1,147 @@
+use super::{authorisation::StaffGuard, node::NodeId};
+use crate::{auth::Caller, tables::audit_log};
+use async_graphql::{Context, Object, ID};
+use chrono::{DateTime, Utc};
+use derive_more::{Deref, DerefMut, From};
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder, Set};
+
+/// Writes an audit record of a mutation, noting the shipment and entities it produced on success
+/// or the error on failure. Failure to write the record is logged rather than returned, such that
+/// the outcome reported to the caller reflects the mutation itself.
+pub async fn record_mutation<T>(
+    ctx: &Context<'_>,
+    operation: &str,
+    input: serde_json::Value,
+    outcome: &async_graphql::Result<T>,
+    resulting_ids: impl FnOnce(&T) -> (Option<u32>, Vec<NodeId>),
+) {
+    let (Ok(database), Ok(caller)) = (ctx.data::<DatabaseConnection>(), ctx.data::<Caller>())
+    else {
+        tracing::error!("Could not audit {}: missing context data", operation);
+        return;
+    };
+    let (shipment_id, entities, error) = match outcome {
+        Ok(value) => {
+            let (shipment_id, entities) = resulting_ids(value);
+            (shipment_id, entities, None)
+        }
+        Err(err) => (None, Vec::new(), Some(err.message.clone())),
+    };
+    let resulting_ids = entities
+        .into_iter()
+        .map(|entity| ID::from(entity).0)
+        .collect::<Vec<_>>();
+    let insert = audit_log::Entity::insert(audit_log::ActiveModel {
+        timestamp: Set(chrono::Utc::now()),
+        caller: Set(caller.identity()),
+        operation: Set(operation.to_string()),
+        input: Set(input.to_string()),
+        shipment_id: Set(shipment_id),
+        resulting_ids: Set(
+            serde_json::to_string(&resulting_ids).expect("Resulting ids are serializable")
+        ),
+        succeeded: Set(error.is_none()),
+        error: Set(error),
+        ..Default::default()
+    })
+    .exec(database)
+    .await;
+    if let Err(err) = insert {
+        tracing::error!(
+            "Could not audit {} by {}: {}",
+            operation,
+            caller.identity(),
+            err
+        );
+    }
+}
+
+/// Writes an audit record of a mutation which a guard refused to resolve, using the name and
+/// arguments of the guarded field as the operation and its input
+pub async fn record_rejection(ctx: &Context<'_>, error: &async_graphql::Error) {
+    let field = ctx.field();
+    let input = field
+        .arguments()
+        .map(|arguments| {
+            serde_json::Value::Object(
+                arguments
+                    .into_iter()
+                    .filter_map(|(name, value)| Some((name.to_string(), value.into_json().ok()?)))
+                    .collect(),
+            )
+        })
+        .unwrap_or_default();
+    record_mutation::<()>(ctx, field.name(), input, &Err(error.clone()), |_| {
+        (None, Vec::new())
+    })
+    .await;
+}
+
+#[derive(Debug, Clone, From, Deref, DerefMut)]
+pub struct AuditRecord(audit_log::Model);
+
+#[Object]
+impl AuditRecord {
+    async fn id(&self) -> &u64 {
+        &self.id
+    }
+
+    async fn timestamp(&self) -> &DateTime<Utc> {
+        &self.timestamp
+    }
+
+    async fn caller(&self) -> &String {
+        &self.caller
+    }
+
+    async fn operation(&self) -> &String {
+        &self.operation
+    }
+
+    /// The arguments of the mutation, as JSON
+    async fn input(&self) -> &String {
+        &self.input
+    }
+
+    async fn shipment_id(&self) -> &Option<u32> {
+        &self.shipment_id
+    }
+
+    /// The node ids of the entities created or modified by the mutation
+    async fn resulting_ids(&self) -> async_graphql::Result<Vec<ID>> {
+        Ok(serde_json::from_str::<Vec<String>>(&self.resulting_ids)?
+            .into_iter()
+            .map(ID)
+            .collect())
+    }
+
+    async fn succeeded(&self) -> &bool {
+        &self.succeeded
+    }
+
+    async fn error(&self) -> &Option<String> {
+        &self.error
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct AuditQuery;
+
+#[Object]
+impl AuditQuery {
+    #[graphql(guard = "StaffGuard")]
+    async fn audit_log(
+        &self,
+        ctx: &Context<'_>,
+        shipment_id: u32,
+    ) -> async_graphql::Result<Vec<AuditRecord>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        audit_log::Entity::find()
+            .filter(audit_log::Column::ShipmentId.eq(shipment_id))
+            .order_by_asc(audit_log::Column::Id)
+            .all(database)
+            .await
+            .map(|records| records.into_iter().map(AuditRecord::from).collect())
+            .map_err(async_graphql::Error::from)
+    }
+}
@@ -0,0 +1,283 @@
+use super::audit;
+use crate::auth::{Caller, Scope, User};
+use async_graphql::{Context, Guard};
+use models::{
+    bl_sample, bl_session, container, dewar, person, proposal, proposal_has_person,
+    session_has_person, shipping,
+};
+use sea_orm::{
+    sea_query::{Query, SelectStatement},
+    ColumnTrait, Condition, DatabaseConnection, EntityTrait, QueryFilter, QuerySelect,
+};
+use tokio::sync::OnceCell;
+
+/// The proposals whose shipments, dewars, pucks, pins and people a caller may see and modify
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProposalAccess {
+    /// Staff may access every proposal
+    All,
+    /// Users may access the proposals they are a member of
+    Only(Vec<u32>),
+}
+
+/// The proposals accessible to the caller of a request, or of every request on a websocket
+/// connection, determined once by the first resolver which needs them
+#[derive(Debug, Default)]
+pub struct ProposalAccessCache(OnceCell<ProposalAccess>);
+
+impl ProposalAccess {
+    /// Determines the proposals accessible to the caller of a resolver, reusing those determined
+    /// earlier in the request if a [`ProposalAccessCache`] was supplied
+    pub async fn of_caller(ctx: &Context<'_>) -> async_graphql::Result<Self> {
+        let caller = ctx.data::<Caller>()?;
+        let database = ctx.data::<DatabaseConnection>()?;
+        match ctx.data_opt::<ProposalAccessCache>() {
+            Some(cache) => cache
+                .0
+                .get_or_try_init(|| Self::of(caller, database))
+                .await
+                .cloned(),
+            None => Self::of(caller, database).await,
+        }
+    }
+
+    /// Determines the proposals accessible to the caller, from their membership of each proposal
+    /// or of any of its sessions. Services may access every proposal, provided their API key
+    /// permits reading.
+    pub async fn of(caller: &Caller, database: &DatabaseConnection) -> async_graphql::Result<Self> {
+        let user = match caller {
+            Caller::User(user) if !user.staff => user,
+            Caller::User(_) => return Ok(Self::All),
+            Caller::Service(_) if caller.may_read() => return Ok(Self::All),
+            Caller::Service(_) => {
+                return Err(async_graphql::Error::new(format!(
+                    "The API key lacks the {} scope",
+                    Scope::ReadOnly.as_str()
+                )))
+            }
+        };
+        let login = user.login.as_ref().ok_or_else(|| {
+            async_graphql::Error::new(format!("Caller {} has no FedID", user.subject))
+        })?;
+        let person_ids = Query::select()
+            .column(person::Column::PersonId)
+            .from(person::Entity)
+            .and_where(person::Column::Login.eq(login.as_str()))
+            .to_owned();
+        let proposal_ids = proposal::Entity::find()
+            .select_only()
+            .column(proposal::Column::ProposalId)
+            .filter(
+                Condition::any()
+                    .add(proposal::Column::PersonId.in_subquery(person_ids.clone()))
+                    .add(
+                        proposal::Column::ProposalId.in_subquery(
+                            Query::select()
+                                .column(proposal_has_person::Column::ProposalId)
+                                .from(proposal_has_person::Entity)
+                                .and_where(
+                                    proposal_has_person::Column::PersonId
+                                        .in_subquery(person_ids.clone()),
+                                )
+                                .to_owned(),
+                        ),
+                    )
+                    .add(
+                        proposal::Column::ProposalId.in_subquery(
+                            Query::select()
+                                .column(bl_session::Column::ProposalId)
+                                .from(bl_session::Entity)
+                                .and_where(
+                                    bl_session::Column::SessionId.in_subquery(
+                                        Query::select()
+                                            .column(session_has_person::Column::SessionId)
+                                            .from(session_has_person::Entity)
+                                            .and_where(
+                                                session_has_person::Column::PersonId
+                                                    .in_subquery(person_ids),
+                                            )
+                                            .to_owned(),
+                                    ),
+                                )
+                                .to_owned(),
+                        ),
+                    ),
+            )
+            .into_tuple::<u32>()
+            .all(database)
+            .await?;
+        Ok(Self::Only(proposal_ids))
+    }
+
+    pub fn allows(&self, proposal_id: u32) -> bool {
+        match self {
+            Self::All => true,
+            Self::Only(proposal_ids) => proposal_ids.contains(&proposal_id),
+        }
+    }
+
+    /// Fails unless the proposal is accessible, for use before modifying it
+    pub fn check(&self, proposal_id: u32) -> async_graphql::Result<()> {
+        if self.allows(proposal_id) {
+            Ok(())
+        } else {
+            Err(async_graphql::Error::new(format!(
+                "Not permitted to access proposal {}",
+                proposal_id
+            )))
+        }
+    }
+
+    /// Builds a condition matching only the accessible rows, or all rows for staff
+    fn restrict(&self, condition: impl FnOnce(&[u32]) -> Condition) -> Condition {
+        match self {
+            Self::All => Condition::all(),
+            Self::Only(proposal_ids) => condition(proposal_ids),
+        }
+    }
+
+    pub fn proposals(&self) -> Condition {
+        self.restrict(|proposal_ids| {
+            Condition::all().add(proposal::Column::ProposalId.is_in(proposal_ids.iter().copied()))
+        })
+    }
+
+    pub fn shipments(&self) -> Condition {
+        self.restrict(|proposal_ids| {
+            Condition::all().add(shipping::Column::ProposalId.is_in(proposal_ids.iter().copied()))
+        })
+    }
+
+    pub fn dewars(&self) -> Condition {
+        self.restrict(|proposal_ids| {
+            Condition::all().add(dewar::Column::ShippingId.in_subquery(shipping_ids(proposal_ids)))
+        })
+    }
+
+    pub fn pucks(&self) -> Condition {
+        self.restrict(|proposal_ids| {
+            Condition::all().add(container::Column::DewarId.in_subquery(dewar_ids(proposal_ids)))
+        })
+    }
+
+    pub fn pins(&self) -> Condition {
+        self.restrict(|proposal_ids| {
+            Condition::all()
+                .add(bl_sample::Column::ContainerId.in_subquery(container_ids(proposal_ids)))
+        })
+    }
+
+    /// Matches the principal investigators, members and session members of accessible proposals
+    pub fn people(&self) -> Condition {
+        self.restrict(|proposal_ids| {
+            let proposal_ids = proposal_ids.iter().copied().collect::<Vec<_>>();
+            Condition::any()
+                .add(
+                    person::Column::PersonId.in_subquery(
+                        Query::select()
+                            .column(proposal::Column::PersonId)
+                            .from(proposal::Entity)
+                            .and_where(proposal::Column::ProposalId.is_in(proposal_ids.clone()))
+                            .to_owned(),
+                    ),
+                )
+                .add(
+                    person::Column::PersonId.in_subquery(
+                        Query::select()
+                            .column(proposal_has_person::Column::PersonId)
+                            .from(proposal_has_person::Entity)
+                            .and_where(
+                                proposal_has_person::Column::ProposalId.is_in(proposal_ids.clone()),
+                            )
+                            .to_owned(),
+                    ),
+                )
+                .add(
+                    person::Column::PersonId.in_subquery(
+                        Query::select()
+                            .column(session_has_person::Column::PersonId)
+                            .from(session_has_person::Entity)
+                            .and_where(
+                                session_has_person::Column::SessionId.in_subquery(
+                                    Query::select()
+                                        .column(bl_session::Column::SessionId)
+                                        .from(bl_session::Entity)
+                                        .and_where(
+                                            bl_session::Column::ProposalId.is_in(proposal_ids),
+                                        )
+                                        .to_owned(),
+                                ),
+                            )
+                            .to_owned(),
+                    ),
+                )
+        })
+    }
+}
+
+fn shipping_ids(proposal_ids: &[u32]) -> SelectStatement {
+    Query::select()
+        .column(shipping::Column::ShippingId)
+        .from(shipping::Entity)
+        .and_where(shipping::Column::ProposalId.is_in(proposal_ids.iter().copied()))
+        .to_owned()
+}
+
+fn dewar_ids(proposal_ids: &[u32]) -> SelectStatement {
+    Query::select()
+        .column(dewar::Column::DewarId)
+        .from(dewar::Entity)
+        .and_where(dewar::Column::ShippingId.in_subquery(shipping_ids(proposal_ids)))
+        .to_owned()
+}
+
+fn container_ids(proposal_ids: &[u32]) -> SelectStatement {
+    Query::select()
+        .column(container::Column::ContainerId)
+        .from(container::Entity)
+        .and_where(container::Column::DewarId.in_subquery(dewar_ids(proposal_ids)))
+        .to_owned()
+}
+
+/// Permits only staff to resolve the guarded field
+pub struct StaffGuard;
+
+#[async_trait::async_trait]
+impl Guard for StaffGuard {
+    async fn check(&self, ctx: &Context<'_>) -> async_graphql::Result<()> {
+        if let Caller::User(User { staff: true, .. }) = ctx.data::<Caller>()? {
+            Ok(())
+        } else {
+            Err(async_graphql::Error::new(
+                "Only staff may access this field",
+            ))
+        }
+    }
+}
+
+/// Permits only callers whose API key holds the scope, or users, to resolve the guarded mutation.
+/// Rejected calls are recorded in the audit log.
+pub struct ScopeGuard(pub Scope);
+
+#[async_trait::async_trait]
+impl Guard for ScopeGuard {
+    async fn check(&self, ctx: &Context<'_>) -> async_graphql::Result<()> {
+        let permitted = ctx
+            .data::<Caller>()
+            .map(|caller| caller.has_scope(self.0))
+            .and_then(|permitted| {
+                if permitted {
+                    Ok(())
+                } else {
+                    Err(async_graphql::Error::new(format!(
+                        "The API key lacks the {} scope",
+                        self.0.as_str()
+                    )))
+                }
+            });
+        if let Err(err) = &permitted {
+            audit::record_rejection(ctx, err).await;
+        }
+        permitted
+    }
+}
@@ -1,18 +1,26 @@
-use super::puck::{Puck, PuckInput};
+use super::{
+    authorisation::ProposalAccess,
+    node::{NodeId, NodeType},
+    puck::{Puck, PuckInput},
+    shipment::Shipment,
+};
 use async_graphql::{
     futures_util::{stream::FuturesOrdered, StreamExt},
-    Context, InputObject, Object,
+    Context, InputObject, Object, ID,
 };
 use derive_more::{Deref, DerefMut, From};
 use models::{
     bl_sample, container,
     dewar::{ActiveModel, Column, Entity, Model},
+    shipping,
 };
 use sea_orm::{
-    ColumnTrait, DatabaseConnection, DbErr, EntityTrait, InsertResult, QueryFilter, QueryTrait, Set,
+    ColumnTrait, ConnectionTrait, DatabaseConnection, DbErr, EntityTrait, InsertResult,
+    QueryFilter, QueryTrait, Set,
 };
+use serde::Serialize;
 
-#[derive(Debug, InputObject, Clone)]
+#[derive(Debug, InputObject, Clone, PartialEq, Serialize)]
 pub struct DewarInput {
     pub code: String,
     pub pucks: Vec<PuckInput>,
@@ -22,7 +30,8 @@ impl DewarInput {
     pub async fn insert_as_child_recursive(
         self,
         shipment_id: u32,
-        database: &DatabaseConnection,
+        proposal_id: u32,
+        database: &impl ConnectionTrait,
     ) -> Result<
         (
             InsertResult<ActiveModel>,
@@ -44,7 +53,9 @@ impl DewarInput {
         let puck_inserts = self
             .pucks
             .into_iter()
-            .map(|puck| puck.insert_as_child_recursive(insert.last_insert_id, database))
+            .map(|puck| {
+                puck.insert_as_child_recursive(insert.last_insert_id, proposal_id, database)
+            })
             .collect::<FuturesOrdered<_>>()
             .collect::<Vec<_>>()
             .await
@@ -78,6 +89,21 @@ impl Dewar {
         &self.dewar_id
     }
 
+    async fn node_id(&self) -> ID {
+        NodeId::new(NodeType::Dewar, self.dewar_id).into()
+    }
+
+    async fn shipment(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Shipment>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        Ok(match self.shipping_id {
+            Some(shipping_id) => shipping::Entity::find_by_id(shipping_id)
+                .one(database)
+                .await?
+                .map(Shipment::from),
+            None => None,
+        })
+    }
+
     async fn code(&self) -> &Option<String> {
         &self.code
     }
@@ -99,13 +125,25 @@ pub struct DewarQuery;
 
 #[Object]
 impl DewarQuery {
+    async fn dewar(&self, ctx: &Context<'_>, id: u32) -> async_graphql::Result<Option<Dewar>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
+        Ok(Entity::find_by_id(id)
+            .filter(access.dewars())
+            .one(database)
+            .await?
+            .map(Dewar::from))
+    }
+
     async fn dewars(
         &self,
         ctx: &Context<'_>,
         shipment_id: Option<u32>,
     ) -> async_graphql::Result<Vec<Dewar>> {
         let database = ctx.data::<DatabaseConnection>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
         Entity::find()
+            .filter(access.dewars())
             .apply_if(shipment_id, |query, shipment_id| {
                 query.filter(Column::ShippingId.eq(shipment_id))
             })
@@ -0,0 +1,29 @@
+use async_graphql::Object;
+use derive_more::{Deref, DerefMut, From};
+use models::laboratory;
+
+#[derive(Debug, Clone, From, Deref, DerefMut)]
+pub struct Laboratory(laboratory::Model);
+
+#[Object]
+impl Laboratory {
+    async fn id(&self) -> &u32 {
+        &self.laboratory_id
+    }
+
+    async fn name(&self) -> &Option<String> {
+        &self.name
+    }
+
+    async fn address(&self) -> &Option<String> {
+        &self.address
+    }
+
+    async fn city(&self) -> &Option<String> {
+        &self.city
+    }
+
+    async fn country(&self) -> &Option<String> {
+        &self.country
+    }
+}
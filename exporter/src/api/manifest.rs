@@ -0,0 +1,124 @@
+use super::{
+    audit,
+    authorisation::{ProposalAccess, ScopeGuard},
+    node::NodeId,
+    shipment::{create_shipment, Shipment},
+};
+use crate::{
+    auth::Scope,
+    manifest::{Manifest, ManifestError, ManifestFormat},
+};
+use async_graphql::{Context, Object, SimpleObject, Upload};
+use sea_orm::DatabaseConnection;
+use std::io::Read;
+
+/// A problem with a manifest, located by the line on which it occurs if known
+#[derive(Debug, Clone, SimpleObject)]
+pub struct ManifestRowError {
+    line: Option<u64>,
+    message: String,
+}
+
+impl From<ManifestError> for ManifestRowError {
+    fn from(value: ManifestError) -> Self {
+        Self {
+            line: value.line,
+            message: value.message,
+        }
+    }
+}
+
+/// The outcome of importing a manifest. If any errors are reported no shipment is created.
+#[derive(Debug, Clone, SimpleObject)]
+pub struct ManifestImport {
+    dry_run: bool,
+    dewars: u32,
+    pucks: u32,
+    pins: u32,
+    errors: Vec<ManifestRowError>,
+    shipment: Option<Shipment>,
+}
+
+#[derive(Debug, Default)]
+pub struct ManifestMutation;
+
+#[Object]
+impl ManifestMutation {
+    /// Creates a shipment from a CSV or XLSX manifest, with a row for each pin. The format is
+    /// inferred from the file name. If `dryRun` is set the manifest is validated but no shipment
+    /// is created.
+    #[graphql(guard = "ScopeGuard(Scope::ShipmentWrite)")]
+    async fn create_shipment_from_manifest(
+        &self,
+        ctx: &Context<'_>,
+        proposal_id: u32,
+        csv: Upload,
+        #[graphql(default = false)] dry_run: bool,
+    ) -> async_graphql::Result<ManifestImport> {
+        let upload = csv.value(ctx)?;
+        let input = serde_json::json!({
+            "proposalId": proposal_id,
+            "fileName": upload.filename,
+            "dryRun": dry_run,
+        });
+        let outcome = import_manifest(ctx, proposal_id, upload, dry_run).await;
+        audit::record_mutation(
+            ctx,
+            "createShipmentFromManifest",
+            input,
+            &outcome,
+            |(_, created)| match created {
+                Some((shipment, entities)) => (Some(shipment.shipping_id), entities.clone()),
+                None => (None, Vec::new()),
+            },
+        )
+        .await;
+        outcome.map(|(import, _)| import)
+    }
+}
+
+async fn import_manifest(
+    ctx: &Context<'_>,
+    proposal_id: u32,
+    upload: async_graphql::UploadValue,
+    dry_run: bool,
+) -> async_graphql::Result<(ManifestImport, Option<(Shipment, Vec<NodeId>)>)> {
+    let database = ctx.data::<DatabaseConnection>()?;
+    ProposalAccess::of_caller(ctx).await?.check(proposal_id)?;
+    let format = ManifestFormat::from_file_name(&upload.filename);
+    let mut content = Vec::new();
+    upload.into_read().read_to_end(&mut content)?;
+
+    let manifest = match Manifest::parse(&content, format) {
+        Ok(manifest) => manifest,
+        Err(errors) => {
+            return Ok((
+                ManifestImport {
+                    dry_run,
+                    dewars: 0,
+                    pucks: 0,
+                    pins: 0,
+                    errors: errors.into_iter().map(ManifestRowError::from).collect(),
+                    shipment: None,
+                },
+                None,
+            ))
+        }
+    };
+    let errors = manifest.check_proteins(proposal_id, database).await?;
+    let mut import = ManifestImport {
+        dry_run,
+        dewars: manifest.dewar_count() as u32,
+        pucks: manifest.puck_count() as u32,
+        pins: manifest.pin_count() as u32,
+        errors: errors.into_iter().map(ManifestRowError::from).collect(),
+        shipment: None,
+    };
+    if dry_run || !import.errors.is_empty() {
+        return Ok((import, None));
+    }
+
+    let (shipment, entities) = create_shipment(ctx, proposal_id, manifest.into_dewars()).await?;
+    import.shipment = Some(shipment.clone());
+    Ok((import, Some((shipment, entities))))
+}
@@ -1,38 +1,64 @@
+mod audit;
+mod authorisation;
 mod dewar;
+mod laboratory;
+mod manifest;
+mod node;
 mod person;
 mod pin;
 mod proposal;
 mod puck;
 mod shipment;
+mod shipment_event;
+mod webhook;
 
 use self::{
+    audit::AuditQuery,
     dewar::DewarQuery,
+    manifest::ManifestMutation,
+    node::NodeQuery,
     pin::PinQuery,
     proposal::ProposalQuery,
     puck::PuckQuery,
     shipment::{ShipmentQuery, ShipmentSubscription},
+    webhook::WebhookQuery,
     {person::PersonQuery, shipment::ShipmentMutation},
 };
 use async_graphql::{Enum, MergedObject, MergedSubscription, Schema};
+use serde::{Deserialize, Serialize};
+
+pub use self::{
+    authorisation::{ProposalAccess, ProposalAccessCache},
+    dewar::DewarInput,
+    node::{NodeId, NodeType},
+    pin::{find_protein, PinInput},
+    puck::PuckInput,
+    shipment::insert_shipment,
+    shipment_event::ShipmentEvent,
+};
 
 pub type RootSchema = Schema<RootQuery, RootMutation, RootSubscription>;
 
 #[derive(Debug, MergedObject, Default)]
 pub struct RootQuery(
+    AuditQuery,
     DewarQuery,
+    NodeQuery,
     PersonQuery,
     PinQuery,
     ProposalQuery,
     PuckQuery,
     ShipmentQuery,
+    WebhookQuery,
 );
 
 #[derive(Debug, MergedObject, Default)]
-pub struct RootMutation(ShipmentMutation);
+pub struct RootMutation(ManifestMutation, ShipmentMutation);
 
-#[derive(Debug, Enum, PartialEq, Eq, Clone, Copy)]
+#[derive(Debug, Enum, PartialEq, Eq, Clone, Copy, Serialize, Deserialize)]
 pub enum MutationType {
     Created,
+    Updated,
     Deleted,
 }
 
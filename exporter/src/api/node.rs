@@ -0,0 +1,154 @@
+use super::{
+    authorisation::ProposalAccess, dewar::Dewar, person::Person, pin::Pin, proposal::Proposal,
+    puck::Puck, shipment::Shipment,
+};
+use async_graphql::{Context, Interface, Object, ID};
+use models::{bl_sample, container, dewar, person, proposal, shipping};
+use sea_orm::{DatabaseConnection, DbErr, EntityTrait, QueryFilter};
+use serde::{Deserialize, Serialize};
+use std::{fmt::Display, str::FromStr};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum NodeType {
+    Dewar,
+    Person,
+    Pin,
+    Proposal,
+    Puck,
+    Shipment,
+}
+
+impl Display for NodeType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Dewar => "Dewar",
+            Self::Person => "Person",
+            Self::Pin => "Pin",
+            Self::Proposal => "Proposal",
+            Self::Puck => "Puck",
+            Self::Shipment => "Shipment",
+        })
+    }
+}
+
+impl FromStr for NodeType {
+    type Err = async_graphql::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "Dewar" => Ok(Self::Dewar),
+            "Person" => Ok(Self::Person),
+            "Pin" => Ok(Self::Pin),
+            "Proposal" => Ok(Self::Proposal),
+            "Puck" => Ok(Self::Puck),
+            "Shipment" => Ok(Self::Shipment),
+            _ => Err(async_graphql::Error::new(format!(
+                "Unknown node type '{}'",
+                s
+            ))),
+        }
+    }
+}
+
+/// A globally unique identifier, composed of the node type and the ISPyB primary key
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct NodeId {
+    pub node_type: NodeType,
+    pub id: u32,
+}
+
+impl NodeId {
+    pub fn new(node_type: NodeType, id: u32) -> Self {
+        Self { node_type, id }
+    }
+}
+
+impl From<NodeId> for ID {
+    fn from(value: NodeId) -> Self {
+        ID(format!("{}:{}", value.node_type, value.id))
+    }
+}
+
+impl TryFrom<&ID> for NodeId {
+    type Error = async_graphql::Error;
+
+    fn try_from(value: &ID) -> Result<Self, Self::Error> {
+        let malformed =
+            || async_graphql::Error::new(format!("Malformed node id '{}'", value.as_str()));
+        let (node_type, id) = value.split_once(':').ok_or_else(malformed)?;
+        Ok(Self {
+            node_type: node_type.parse()?,
+            id: id.parse().map_err(|_| malformed())?,
+        })
+    }
+}
+
+/// An object which may be refetched by its globally unique id with the `node` query. The id is
+/// exposed as `nodeId` rather than the conventional `id`, as every object type already has an
+/// `id: Int` field holding its ISPyB primary key, which the interface field would conflict with.
+#[derive(Debug, Clone, Interface)]
+#[graphql(field(name = "node_id", ty = "ID"))]
+pub enum Node {
+    Dewar(Dewar),
+    Person(Person),
+    Pin(Pin),
+    Proposal(Proposal),
+    Puck(Puck),
+    Shipment(Shipment),
+}
+
+impl Node {
+    /// Fetches the node with the given id, provided it belongs to an accessible proposal
+    pub async fn fetch(
+        node_id: NodeId,
+        access: &ProposalAccess,
+        database: &DatabaseConnection,
+    ) -> Result<Option<Self>, DbErr> {
+        let NodeId { node_type, id } = node_id;
+        Ok(match node_type {
+            NodeType::Dewar => dewar::Entity::find_by_id(id)
+                .filter(access.dewars())
+                .one(database)
+                .await?
+                .map(|dewar| Node::Dewar(dewar.into())),
+            NodeType::Person => person::Entity::find_by_id(id)
+                .filter(access.people())
+                .one(database)
+                .await?
+                .map(|person| Node::Person(person.into())),
+            NodeType::Pin => bl_sample::Entity::find_by_id(id)
+                .filter(access.pins())
+                .one(database)
+                .await?
+                .map(|pin| Node::Pin(pin.into())),
+            NodeType::Proposal => proposal::Entity::find_by_id(id)
+                .filter(access.proposals())
+                .one(database)
+                .await?
+                .map(|proposal| Node::Proposal(proposal.into())),
+            NodeType::Puck => container::Entity::find_by_id(id)
+                .filter(access.pucks())
+                .one(database)
+                .await?
+                .map(|puck| Node::Puck(puck.into())),
+            NodeType::Shipment => shipping::Entity::find_by_id(id)
+                .filter(access.shipments())
+                .one(database)
+                .await?
+                .map(|shipment| Node::Shipment(shipment.into())),
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct NodeQuery;
+
+#[Object]
+impl NodeQuery {
+    /// Fetches any object by the globally unique id of its `nodeId` field
+    async fn node(&self, ctx: &Context<'_>, id: ID) -> async_graphql::Result<Option<Node>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
+        Ok(Node::fetch(NodeId::try_from(&id)?, &access, database).await?)
+    }
+}
@@ -1,7 +1,16 @@
-use async_graphql::{Context, Object};
+use super::{
+    authorisation::ProposalAccess,
+    laboratory::Laboratory,
+    node::{NodeId, NodeType},
+    proposal::Proposal,
+};
+use async_graphql::{Context, Object, ID};
 use derive_more::{Deref, DerefMut, From};
-use models::person;
-use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryTrait};
+use models::{laboratory, person, proposal, proposal_has_person};
+use sea_orm::{
+    sea_query::Query, ColumnTrait, Condition, DatabaseConnection, EntityTrait, QueryFilter,
+    QueryTrait,
+};
 
 #[derive(Debug, Clone, From, Deref, DerefMut)]
 pub struct Person(person::Model);
@@ -12,6 +21,10 @@ impl Person {
         &self.person_id
     }
 
+    async fn node_id(&self) -> ID {
+        NodeId::new(NodeType::Person, self.person_id).into()
+    }
+
     async fn name(&self) -> String {
         match (&self.given_name, &self.family_name, &self.title) {
             (None, None, None) => "Unknown".to_string(),
@@ -28,6 +41,50 @@ impl Person {
             }
         }
     }
+
+    async fn login(&self) -> &Option<String> {
+        &self.login
+    }
+
+    async fn email_address(&self) -> &Option<String> {
+        &self.email_address
+    }
+
+    async fn laboratory(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Laboratory>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        Ok(match self.laboratory_id {
+            Some(laboratory_id) => laboratory::Entity::find_by_id(laboratory_id)
+                .one(database)
+                .await?
+                .map(Laboratory::from),
+            None => None,
+        })
+    }
+
+    async fn proposals(&self, ctx: &Context<'_>) -> async_graphql::Result<Vec<Proposal>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
+        Ok(proposal::Entity::find()
+            .filter(access.proposals())
+            .filter(
+                Condition::any()
+                    .add(proposal::Column::PersonId.eq(self.person_id))
+                    .add(
+                        proposal::Column::ProposalId.in_subquery(
+                            Query::select()
+                                .column(proposal_has_person::Column::ProposalId)
+                                .from(proposal_has_person::Entity)
+                                .and_where(proposal_has_person::Column::PersonId.eq(self.person_id))
+                                .to_owned(),
+                        ),
+                    ),
+            )
+            .all(database)
+            .await?
+            .into_iter()
+            .map(Proposal::from)
+            .collect())
+    }
 }
 
 #[derive(Debug, Default)]
@@ -35,16 +92,36 @@ pub struct PersonQuery;
 
 #[Object]
 impl PersonQuery {
+    async fn person(&self, ctx: &Context<'_>, id: u32) -> async_graphql::Result<Option<Person>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
+        Ok(person::Entity::find_by_id(id)
+            .filter(access.people())
+            .one(database)
+            .await?
+            .map(Person::from))
+    }
+
     async fn people(
         &self,
         ctx: &Context<'_>,
         id: Option<u32>,
+        login: Option<String>,
+        email_address: Option<String>,
     ) -> async_graphql::Result<Vec<Person>> {
         let database = ctx.data::<DatabaseConnection>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
         person::Entity::find()
+            .filter(access.people())
             .apply_if(id, |query, id| {
                 query.filter(person::Column::PersonId.eq(id))
             })
+            .apply_if(login, |query, login| {
+                query.filter(person::Column::Login.eq(login))
+            })
+            .apply_if(email_address, |query, email_address| {
+                query.filter(person::Column::EmailAddress.eq(email_address))
+            })
             .all(database)
             .await
             .map(|people| people.into_iter().map(Person::from).collect())
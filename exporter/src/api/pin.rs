@@ -1,24 +1,68 @@
-use async_graphql::{Context, InputObject, Object};
+use super::{
+    authorisation::ProposalAccess,
+    node::{NodeId, NodeType},
+    puck::Puck,
+};
+use async_graphql::{Context, InputObject, Object, ID};
 use derive_more::{Deref, DerefMut, From};
-use models::bl_sample::{ActiveModel, Column, Entity, Model};
+use models::{
+    bl_sample::{ActiveModel, Column, Entity, Model},
+    container, crystal, protein,
+};
 use sea_orm::{
-    ColumnTrait, DatabaseConnection, DbErr, EntityTrait, InsertResult, QueryFilter, QueryTrait, Set,
+    ColumnTrait, ConnectionTrait, DatabaseConnection, DbErr, EntityTrait, InsertResult,
+    QueryFilter, QueryTrait, Set,
 };
+use serde::Serialize;
 
-#[derive(Debug, InputObject, Clone)]
+#[derive(Debug, InputObject, Clone, PartialEq, Serialize)]
 pub struct PinInput {
     pub code: String,
+    /// The position of the pin within the puck
+    pub location: Option<String>,
+    pub name: Option<String>,
+    /// The acronym of a protein registered against the proposal, of which the sample is a crystal
+    pub protein_acronym: Option<String>,
+    /// The SMILES of the ligand soaked into the crystal
+    pub smiles: Option<String>,
+    pub comments: Option<String>,
 }
 
 impl PinInput {
     pub async fn insert_as_child(
         self,
         puck_id: u32,
-        database: &DatabaseConnection,
+        proposal_id: u32,
+        database: &impl ConnectionTrait,
     ) -> Result<InsertResult<ActiveModel>, DbErr> {
+        let crystal_id = match self.protein_acronym {
+            Some(protein_acronym) => {
+                let protein = find_protein(&protein_acronym, proposal_id, database)
+                    .await?
+                    .ok_or_else(|| {
+                        DbErr::RecordNotFound(format!(
+                            "No protein with acronym '{}' in proposal {}",
+                            protein_acronym, proposal_id
+                        ))
+                    })?;
+                let crystal_insert = crystal::Entity::insert(crystal::ActiveModel {
+                    protein_id: Set(protein.protein_id),
+                    ..Default::default()
+                })
+                .exec(database)
+                .await?;
+                Some(crystal_insert.last_insert_id)
+            }
+            None => None,
+        };
         Entity::insert(ActiveModel {
             container_id: Set(Some(puck_id)),
             code: Set(Some(self.code)),
+            location: Set(self.location),
+            name: Set(self.name),
+            smiles: Set(self.smiles),
+            comments: Set(self.comments),
+            crystal_id: Set(crystal_id),
             ..Default::default()
         })
         .exec(database)
@@ -26,6 +70,19 @@ impl PinInput {
     }
 }
 
+/// Finds the protein with the given acronym amongst those registered against the proposal
+pub async fn find_protein(
+    protein_acronym: &str,
+    proposal_id: u32,
+    database: &impl ConnectionTrait,
+) -> Result<Option<protein::Model>, DbErr> {
+    protein::Entity::find()
+        .filter(protein::Column::ProposalId.eq(proposal_id))
+        .filter(protein::Column::Acronym.eq(protein_acronym))
+        .one(database)
+        .await
+}
+
 #[derive(Debug, Clone, From, Deref, DerefMut)]
 pub struct Pin(Model);
 
@@ -35,9 +92,40 @@ impl Pin {
         &self.bl_sample_id
     }
 
+    async fn node_id(&self) -> ID {
+        NodeId::new(NodeType::Pin, self.bl_sample_id).into()
+    }
+
+    async fn puck(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Puck>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        Ok(match self.container_id {
+            Some(container_id) => container::Entity::find_by_id(container_id)
+                .one(database)
+                .await?
+                .map(Puck::from),
+            None => None,
+        })
+    }
+
     async fn code(&self) -> &Option<String> {
         &self.code
     }
+
+    async fn location(&self) -> &Option<String> {
+        &self.location
+    }
+
+    async fn name(&self) -> &Option<String> {
+        &self.name
+    }
+
+    async fn smiles(&self) -> &Option<String> {
+        &self.smiles
+    }
+
+    async fn comments(&self) -> &Option<String> {
+        &self.comments
+    }
 }
 
 #[derive(Debug, Default)]
@@ -45,13 +133,25 @@ pub struct PinQuery;
 
 #[Object]
 impl PinQuery {
+    async fn pin(&self, ctx: &Context<'_>, id: u32) -> async_graphql::Result<Option<Pin>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
+        Ok(Entity::find_by_id(id)
+            .filter(access.pins())
+            .one(database)
+            .await?
+            .map(Pin::from))
+    }
+
     async fn pins(
         &self,
         ctx: &Context<'_>,
         puck_id: Option<u32>,
     ) -> async_graphql::Result<Vec<Pin>> {
         let database = ctx.data::<DatabaseConnection>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
         Entity::find()
+            .filter(access.pins())
             .apply_if(puck_id, |query, puck_id| {
                 query.filter(Column::ContainerId.eq(puck_id))
             })
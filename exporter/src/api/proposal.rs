@@ -1,5 +1,9 @@
-use super::person::Person;
-use async_graphql::{Context, Enum, Object};
+use super::{
+    authorisation::ProposalAccess,
+    node::{NodeId, NodeType},
+    person::Person,
+};
+use async_graphql::{Context, Enum, Object, ID};
 use derive_more::{Deref, DerefMut, From};
 use models::{person, proposal, sea_orm_active_enums};
 use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryTrait};
@@ -40,6 +44,10 @@ impl Proposal {
         &self.proposal_id
     }
 
+    async fn node_id(&self) -> ID {
+        NodeId::new(NodeType::Proposal, self.proposal_id).into()
+    }
+
     async fn person(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Person>> {
         let database = ctx.data::<DatabaseConnection>()?;
         Ok(person::Entity::find_by_id(self.person_id)
@@ -70,13 +78,29 @@ pub struct ProposalQuery;
 
 #[Object]
 impl ProposalQuery {
+    async fn proposal(
+        &self,
+        ctx: &Context<'_>,
+        id: u32,
+    ) -> async_graphql::Result<Option<Proposal>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
+        Ok(proposal::Entity::find_by_id(id)
+            .filter(access.proposals())
+            .one(database)
+            .await?
+            .map(Proposal::from))
+    }
+
     async fn proposals(
         &self,
         ctx: &Context<'_>,
         id: Option<u32>,
     ) -> async_graphql::Result<Vec<Proposal>> {
         let database = ctx.data::<DatabaseConnection>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
         proposal::Entity::find()
+            .filter(access.proposals())
             .apply_if(id, |query, id| {
                 query.filter(proposal::Column::ProposalId.eq(id))
             })
@@ -1,18 +1,26 @@
-use super::pin::{Pin, PinInput};
+use super::{
+    authorisation::ProposalAccess,
+    dewar::Dewar,
+    node::{NodeId, NodeType},
+    pin::{Pin, PinInput},
+};
 use async_graphql::{
     futures_util::{stream::FuturesOrdered, StreamExt},
-    Context, InputObject, Object,
+    Context, InputObject, Object, ID,
 };
 use derive_more::{Deref, DerefMut, From};
 use models::{
     bl_sample,
     container::{ActiveModel, Column, Entity, Model},
+    dewar,
 };
 use sea_orm::{
-    ColumnTrait, DatabaseConnection, DbErr, EntityTrait, InsertResult, QueryFilter, QueryTrait, Set,
+    ColumnTrait, ConnectionTrait, DatabaseConnection, DbErr, EntityTrait, InsertResult,
+    QueryFilter, QueryTrait, Set,
 };
+use serde::Serialize;
 
-#[derive(Debug, InputObject, Clone)]
+#[derive(Debug, InputObject, Clone, PartialEq, Serialize)]
 pub struct PuckInput {
     pub code: String,
     pub pins: Vec<PinInput>,
@@ -22,7 +30,8 @@ impl PuckInput {
     pub async fn insert_as_child_recursive(
         self,
         dewar_id: u32,
-        database: &DatabaseConnection,
+        proposal_id: u32,
+        database: &impl ConnectionTrait,
     ) -> Result<
         (
             InsertResult<ActiveModel>,
@@ -41,7 +50,7 @@ impl PuckInput {
         let pin_inserts = self
             .pins
             .into_iter()
-            .map(|pin| pin.insert_as_child(insert.last_insert_id, database))
+            .map(|pin| pin.insert_as_child(insert.last_insert_id, proposal_id, database))
             .collect::<FuturesOrdered<_>>()
             .collect::<Vec<_>>()
             .await
@@ -76,6 +85,21 @@ impl Puck {
         &self.container_id
     }
 
+    async fn node_id(&self) -> ID {
+        NodeId::new(NodeType::Puck, self.container_id).into()
+    }
+
+    async fn dewar(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Dewar>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        Ok(match self.dewar_id {
+            Some(dewar_id) => dewar::Entity::find_by_id(dewar_id)
+                .one(database)
+                .await?
+                .map(Dewar::from),
+            None => None,
+        })
+    }
+
     async fn code(&self) -> &Option<String> {
         &self.code
     }
@@ -97,13 +121,25 @@ pub struct PuckQuery;
 
 #[Object]
 impl PuckQuery {
+    async fn puck(&self, ctx: &Context<'_>, id: u32) -> async_graphql::Result<Option<Puck>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
+        Ok(Entity::find_by_id(id)
+            .filter(access.pucks())
+            .one(database)
+            .await?
+            .map(Puck::from))
+    }
+
     async fn pucks(
         &self,
         ctx: &Context<'_>,
         dewar_id: Option<u32>,
     ) -> async_graphql::Result<Vec<Puck>> {
         let database = ctx.data::<DatabaseConnection>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
         Entity::find()
+            .filter(access.pucks())
             .apply_if(dewar_id, |query, dewar_id| {
                 query.filter(Column::DewarId.eq(dewar_id))
             })
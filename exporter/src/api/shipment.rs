@@ -1,15 +1,39 @@
 use super::{
+    audit,
+    authorisation::{ProposalAccess, ScopeGuard},
     dewar::{Dewar, DewarInput},
+    node::{NodeId, NodeType},
+    pin::find_protein,
     proposal::Proposal,
+    shipment_event::{ShipmentChanged, ShipmentEvent},
+    MutationType,
+};
+use crate::{
+    auth::{Caller, Scope},
+    broker::{lagged_error, SharedEventBroker},
+    config::ShipmentDefaults,
+    metrics,
+    tables::{idempotency_key, is_duplicate_key},
 };
-use crate::broker::EventBroker;
 use async_graphql::{
-    futures_util::{stream::FuturesOrdered, Stream, StreamExt},
-    Context, Object, Subscription,
+    futures_util::{
+        stream::{self, FuturesOrdered},
+        Stream, StreamExt,
+    },
+    Context, Object, Subscription, ID,
 };
 use derive_more::{Deref, DerefMut, From};
 use models::{dewar, proposal, shipping};
-use sea_orm::{ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, QueryTrait, Set};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, Condition, ConnectionTrait, DatabaseConnection,
+    DatabaseTransaction, DbErr, EntityTrait, QueryFilter, QueryTrait, Set, TransactionTrait,
+};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeSet;
+use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+
+/// The number of days after which an idempotency key may be used to create another shipment
+const IDEMPOTENCY_KEY_LIFETIME_DAYS: i64 = 1;
 
 #[derive(Debug, Clone, From, Deref, DerefMut)]
 pub struct Shipment(shipping::Model);
@@ -20,6 +44,10 @@ impl Shipment {
         &self.shipping_id
     }
 
+    async fn node_id(&self) -> ID {
+        NodeId::new(NodeType::Shipment, self.shipping_id).into()
+    }
+
     async fn proposal(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Proposal>> {
         let database = ctx.data::<DatabaseConnection>()?;
         Ok(proposal::Entity::find_by_id(self.proposal_id)
@@ -53,13 +81,29 @@ pub struct ShipmentQuery;
 
 #[Object]
 impl ShipmentQuery {
+    async fn shipment(
+        &self,
+        ctx: &Context<'_>,
+        id: u32,
+    ) -> async_graphql::Result<Option<Shipment>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
+        Ok(shipping::Entity::find_by_id(id)
+            .filter(access.shipments())
+            .one(database)
+            .await?
+            .map(Shipment::from))
+    }
+
     async fn shipments(
         &self,
         ctx: &Context<'_>,
         proposal_id: Option<u32>,
     ) -> async_graphql::Result<Vec<Shipment>> {
         let database = ctx.data_unchecked::<DatabaseConnection>();
+        let access = ProposalAccess::of_caller(ctx).await?;
         shipping::Entity::find()
+            .filter(access.shipments())
             .apply_if(proposal_id, |query, proposal_id| {
                 query.filter(shipping::Column::ProposalId.eq(proposal_id))
             })
@@ -73,56 +117,281 @@ impl ShipmentQuery {
 #[derive(Debug, Default)]
 pub struct ShipmentMutation;
 
-#[derive(Debug, Clone)]
-pub enum ShipmentEvent {
-    Created(Shipment),
-}
-
-static SHIPMENT_EVENT_BROKER: EventBroker<ShipmentEvent> = EventBroker::<ShipmentEvent>::new();
-
 #[Object]
 impl ShipmentMutation {
+    /// Creates a shipment. If an `idempotencyKey` is supplied and a shipment has already been
+    /// created with that key, the existing shipment is returned instead of creating another.
+    #[graphql(guard = "ScopeGuard(Scope::ShipmentWrite)")]
     async fn create_shipment(
         &self,
         ctx: &Context<'_>,
         proposal_id: u32,
         dewars: Vec<DewarInput>,
+        idempotency_key: Option<String>,
     ) -> async_graphql::Result<Shipment> {
-        let database = ctx.data::<DatabaseConnection>()?;
-
-        let shipping_model = shipping::ActiveModel {
-            proposal_id: Set(proposal_id),
-            shipping_name: Set(Some("XChemLab Shipment".to_string())),
-            comments: Set(Some(
-                "Automatically generated by XChemLab DLS i04-1 Exporter".to_string(),
-            )),
-            ..Default::default()
+        let payload = serde_json::json!({ "proposalId": proposal_id, "dewars": dewars });
+        let input = serde_json::json!({ "payload": payload, "idempotencyKey": idempotency_key });
+        let outcome = match idempotency_key {
+            Some(key) => {
+                create_shipment_idempotently(ctx, key, &payload, proposal_id, dewars).await
+            }
+            None => create_shipment(ctx, proposal_id, dewars).await,
         };
-        let shipping_insert = shipping::Entity::insert(shipping_model)
-            .exec(database)
-            .await?;
+        audit::record_mutation(
+            ctx,
+            "createShipment",
+            input,
+            &outcome,
+            |(shipment, entities)| (Some(shipment.shipping_id), entities.clone()),
+        )
+        .await;
+        outcome.map(|(shipment, _)| shipment)
+    }
+}
 
-        dewars
-            .into_iter()
-            .map(|dewar| dewar.insert_as_child_recursive(shipping_insert.last_insert_id, database))
-            .collect::<FuturesOrdered<_>>()
-            .collect::<Vec<_>>()
-            .await
-            .into_iter()
-            .collect::<Result<Vec<_>, DbErr>>()?;
+/// Reserves the idempotency key and creates the shipment within a single transaction, such that
+/// concurrent retries wait for the first to finish, or replays the shipment previously created
+/// with the key. Keys expire after a day, after which they may be used again.
+async fn create_shipment_idempotently(
+    ctx: &Context<'_>,
+    key: String,
+    payload: &serde_json::Value,
+    proposal_id: u32,
+    dewars: Vec<DewarInput>,
+) -> async_graphql::Result<(Shipment, Vec<NodeId>)> {
+    let database = ctx.data::<DatabaseConnection>()?;
+    let event_broker = ctx.data::<SharedEventBroker<ShipmentEvent>>()?;
+    let defaults = ctx.data::<ShipmentDefaults>()?;
+    let caller = ctx.data::<Caller>()?.identity();
+    let payload_hash = hex::encode(Sha256::digest(payload.to_string().as_bytes()));
+    ProposalAccess::of_caller(ctx).await?.check(proposal_id)?;
+    check_proteins(proposal_id, &dewars, database).await?;
 
-        let created_shipping = shipping::Entity::find_by_id(shipping_insert.last_insert_id)
-            .one(database)
+    let transaction = database.begin().await?;
+    let now = chrono::Utc::now();
+    idempotency_key::Entity::delete_many()
+        .filter(idempotency_key::Column::Key.eq(key.as_str()))
+        .filter(
+            Condition::any()
+                .add(
+                    idempotency_key::Column::CreatedAt
+                        .lt(now - chrono::Duration::days(IDEMPOTENCY_KEY_LIFETIME_DAYS)),
+                )
+                .add(idempotency_key::Column::ShipmentId.is_null()),
+        )
+        .exec(&transaction)
+        .await?;
+    let reservation = idempotency_key::Entity::insert(idempotency_key::ActiveModel {
+        key: Set(key.clone()),
+        caller: Set(caller.clone()),
+        payload_hash: Set(payload_hash.clone()),
+        shipment_id: Set(None),
+        created_at: Set(now),
+    })
+    .exec(&transaction)
+    .await;
+    match reservation {
+        Ok(_) => {}
+        Err(err) if is_duplicate_key(&err) => {
+            transaction.rollback().await?;
+            let existing = idempotency_key::Entity::find_by_id(key)
+                .one(database)
+                .await?
+                .ok_or(err)?;
+            return replay_shipment(existing, &caller, &payload_hash, database).await;
+        }
+        Err(err) => return Err(err.into()),
+    }
+
+    let (shipment, entities, events) =
+        insert_shipment_within(proposal_id, dewars, defaults, &transaction).await?;
+    idempotency_key::ActiveModel {
+        key: Set(key),
+        shipment_id: Set(Some(shipment.shipping_id)),
+        ..Default::default()
+    }
+    .update(&transaction)
+    .await?;
+    transaction.commit().await?;
+    publish_created(events, event_broker);
+    Ok((shipment, entities))
+}
+
+/// Returns the shipment previously created with an idempotency key, provided the replayed request
+/// was made by the same caller with the same payload
+async fn replay_shipment(
+    existing: idempotency_key::Model,
+    caller: &str,
+    payload_hash: &str,
+    database: &DatabaseConnection,
+) -> async_graphql::Result<(Shipment, Vec<NodeId>)> {
+    if existing.caller != caller || existing.payload_hash != payload_hash {
+        return Err(async_graphql::Error::new(format!(
+            "Idempotency key '{}' has already been used for a different request",
+            existing.key
+        )));
+    }
+    let shipment_id = existing.shipment_id.ok_or_else(|| {
+        async_graphql::Error::new(format!(
+            "The request with idempotency key '{}' did not complete",
+            existing.key
+        ))
+    })?;
+    let shipment = shipping::Entity::find_by_id(shipment_id)
+        .one(database)
+        .await?
+        .map(Shipment::from)
+        .ok_or_else(|| {
+            async_graphql::Error::new(format!(
+                "Shipment {} created with idempotency key '{}' no longer exists",
+                shipment_id, existing.key
+            ))
+        })?;
+    Ok((shipment, vec![NodeId::new(NodeType::Shipment, shipment_id)]))
+}
+
+/// Creates a shipment on behalf of the caller, provided they may access the proposal
+pub(super) async fn create_shipment(
+    ctx: &Context<'_>,
+    proposal_id: u32,
+    dewars: Vec<DewarInput>,
+) -> async_graphql::Result<(Shipment, Vec<NodeId>)> {
+    let database = ctx.data::<DatabaseConnection>()?;
+    let event_broker = ctx.data::<SharedEventBroker<ShipmentEvent>>()?;
+    let defaults = ctx.data::<ShipmentDefaults>()?;
+    ProposalAccess::of_caller(ctx).await?.check(proposal_id)?;
+    insert_shipment(proposal_id, dewars, defaults, database, event_broker).await
+}
+
+/// Fails unless every protein acronym amongst the pins is registered against the proposal, such
+/// that no rows are written for a shipment which cannot be created in full
+async fn check_proteins(
+    proposal_id: u32,
+    dewars: &[DewarInput],
+    database: &impl ConnectionTrait,
+) -> async_graphql::Result<()> {
+    let protein_acronyms = dewars
+        .iter()
+        .flat_map(|dewar| dewar.pucks.iter())
+        .flat_map(|puck| puck.pins.iter())
+        .filter_map(|pin| pin.protein_acronym.as_deref())
+        .collect::<BTreeSet<_>>();
+    let mut unknown = Vec::new();
+    for protein_acronym in protein_acronyms {
+        if find_protein(protein_acronym, proposal_id, database)
             .await?
-            .map(Shipment::from)
-            .ok_or(async_graphql::Error::new(format!(
-                "Inserted model at {} but could not retrieve copy",
-                shipping_insert.last_insert_id
-            )))?;
+            .is_none()
+        {
+            unknown.push(format!("'{}'", protein_acronym));
+        }
+    }
+    if unknown.is_empty() {
+        Ok(())
+    } else {
+        Err(async_graphql::Error::new(format!(
+            "No protein with acronym {} in proposal {}",
+            unknown.join(", "),
+            proposal_id
+        )))
+    }
+}
+
+/// Inserts a shipment along with its dewars, pucks and pins, and an event for each, within a
+/// single transaction, publishing the events once it has been committed
+pub async fn insert_shipment(
+    proposal_id: u32,
+    dewars: Vec<DewarInput>,
+    defaults: &ShipmentDefaults,
+    database: &DatabaseConnection,
+    event_broker: &SharedEventBroker<ShipmentEvent>,
+) -> async_graphql::Result<(Shipment, Vec<NodeId>)> {
+    check_proteins(proposal_id, &dewars, database).await?;
+    let transaction = database.begin().await?;
+    let (shipment, entities, events) =
+        insert_shipment_within(proposal_id, dewars, defaults, &transaction).await?;
+    transaction.commit().await?;
+    publish_created(events, event_broker);
+    Ok((shipment, entities))
+}
+
+/// Inserts a shipment along with its dewars, pucks and pins, and records an event for each, within
+/// the transaction. The events must only be published once the transaction has been committed.
+async fn insert_shipment_within(
+    proposal_id: u32,
+    dewars: Vec<DewarInput>,
+    defaults: &ShipmentDefaults,
+    transaction: &DatabaseTransaction,
+) -> async_graphql::Result<(Shipment, Vec<NodeId>, Vec<ShipmentEvent>)> {
+    let shipping_model = shipping::ActiveModel {
+        proposal_id: Set(proposal_id),
+        shipping_name: Set(Some(defaults.name.clone())),
+        creation_date: Set(Some(chrono::Local::now().naive_local())),
+        comments: Set(defaults.comments.clone()),
+        ..Default::default()
+    };
+    let shipping_insert = shipping::Entity::insert(shipping_model)
+        .exec(transaction)
+        .await?;
 
-        SHIPMENT_EVENT_BROKER.publish(ShipmentEvent::Created(created_shipping.clone()));
+    let dewar_inserts = dewars
+        .into_iter()
+        .map(|dewar| {
+            dewar.insert_as_child_recursive(
+                shipping_insert.last_insert_id,
+                proposal_id,
+                transaction,
+            )
+        })
+        .collect::<FuturesOrdered<_>>()
+        .collect::<Vec<_>>()
+        .await
+        .into_iter()
+        .collect::<Result<Vec<_>, DbErr>>()?;
 
-        Ok(created_shipping)
+    let created_shipping = shipping::Entity::find_by_id(shipping_insert.last_insert_id)
+        .one(transaction)
+        .await?
+        .map(Shipment::from)
+        .ok_or(async_graphql::Error::new(format!(
+            "Inserted model at {} but could not retrieve copy",
+            shipping_insert.last_insert_id
+        )))?;
+
+    let mut created_entities = vec![NodeId::new(
+        NodeType::Shipment,
+        created_shipping.shipping_id,
+    )];
+    for (dewar_insert, puck_inserts) in dewar_inserts {
+        created_entities.push(NodeId::new(NodeType::Dewar, dewar_insert.last_insert_id));
+        for (puck_insert, pin_inserts) in puck_inserts {
+            created_entities.push(NodeId::new(NodeType::Puck, puck_insert.last_insert_id));
+            for pin_insert in pin_inserts {
+                created_entities.push(NodeId::new(NodeType::Pin, pin_insert.last_insert_id));
+            }
+        }
+    }
+    ShipmentEvent::lock_log(transaction).await?;
+    let mut events = Vec::with_capacity(created_entities.len());
+    for entity in created_entities.iter().copied() {
+        events.push(
+            ShipmentEvent::record(
+                MutationType::Created,
+                proposal_id,
+                created_shipping.shipping_id,
+                entity,
+                transaction,
+            )
+            .await?,
+        );
+    }
+    Ok((created_shipping, created_entities, events))
+}
+
+/// Publishes the events of a committed shipment creation
+fn publish_created(events: Vec<ShipmentEvent>, event_broker: &SharedEventBroker<ShipmentEvent>) {
+    for event in events {
+        metrics::record_created(event.entity.node_type);
+        event_broker.publish(event);
     }
 }
 
@@ -131,15 +400,93 @@ pub struct ShipmentSubscription;
 
 #[Subscription]
 impl ShipmentSubscription {
-    async fn shipment_created(&self) -> impl Stream<Item = Shipment> {
-        SHIPMENT_EVENT_BROKER
-            .subscribe()
-            .filter_map(move |event| async move {
-                if let Ok(ShipmentEvent::Created(shipment)) = event {
-                    Some(shipment)
-                } else {
+    async fn shipment_created(
+        &self,
+        ctx: &Context<'_>,
+    ) -> async_graphql::Result<impl Stream<Item = async_graphql::Result<Shipment>>> {
+        let database = ctx.data::<DatabaseConnection>()?.clone();
+        let event_broker = ctx.data::<SharedEventBroker<ShipmentEvent>>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
+        Ok(event_broker.subscribe().filter_map(move |event| {
+            let database = database.clone();
+            let created = match event {
+                Ok(ShipmentEvent {
+                    mutation_type: MutationType::Created,
+                    proposal_id,
+                    entity:
+                        NodeId {
+                            node_type: NodeType::Shipment,
+                            id,
+                        },
+                    ..
+                }) if access.allows(proposal_id) => Some(Ok(id)),
+                Ok(_) => None,
+                Err(BroadcastStreamRecvError::Lagged(missed)) => Some(Err(lagged_error(missed))),
+            };
+            async move {
+                match created? {
+                    Ok(id) => shipping::Entity::find_by_id(id)
+                        .one(&database)
+                        .await
+                        .map_err(async_graphql::Error::from)
+                        .map(|shipping| shipping.map(Shipment::from))
+                        .transpose(),
+                    Err(err) => Some(Err(err)),
+                }
+            }
+        }))
+    }
+
+    /// Emits an event whenever the shipment, or any dewar, puck or pin beneath it, changes.
+    /// If `since` is supplied, events recorded after that sequence number are replayed first.
+    /// Updates and deletions made outside of the exporter are only emitted while ISPyB is polled.
+    async fn shipment_changed(
+        &self,
+        ctx: &Context<'_>,
+        proposal_id: Option<u32>,
+        id: Option<u32>,
+        since: Option<u64>,
+    ) -> async_graphql::Result<impl Stream<Item = async_graphql::Result<ShipmentChanged>>> {
+        let database = ctx.data::<DatabaseConnection>()?.clone();
+        let event_broker = ctx.data::<SharedEventBroker<ShipmentEvent>>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
+        let live_events = event_broker.subscribe().map(|event| {
+            event
+                .map(|event| (false, event))
+                .map_err(|BroadcastStreamRecvError::Lagged(missed)| lagged_error(missed))
+        });
+        let replayed_events = match since {
+            Some(since) => ShipmentEvent::replay_all(since, database)
+                .map(|event| {
+                    event
+                        .map(|event| (true, event))
+                        .map_err(async_graphql::Error::from)
+                })
+                .left_stream(),
+            None => stream::empty().right_stream(),
+        };
+        // Live events published while replaying are skipped if they were also replayed
+        let mut replayed_until = since;
+        Ok(replayed_events.chain(live_events).filter_map(move |event| {
+            let changed = match event {
+                Ok((false, event))
+                    if replayed_until
+                        .map_or(false, |replayed_until| event.sequence <= replayed_until) =>
+                {
                     None
                 }
-            })
+                Ok((replayed, event)) => {
+                    if replayed {
+                        replayed_until = Some(event.sequence);
+                    }
+                    (access.allows(event.proposal_id)
+                        && proposal_id.map_or(true, |proposal_id| proposal_id == event.proposal_id)
+                        && id.map_or(true, |id| id == event.shipment_id))
+                    .then(|| Ok(ShipmentChanged::from(event)))
+                }
+                Err(err) => Some(Err(err)),
+            };
+            async move { changed }
+        }))
     }
 }
@@ -0,0 +1,223 @@
+use super::{
+    authorisation::ProposalAccess,
+    node::{Node, NodeId, NodeType},
+    MutationType,
+};
+use crate::tables::{event_lock, event_log};
+use async_graphql::{
+    futures_util::{stream, Stream, TryStreamExt},
+    Context, Object, ID,
+};
+use derive_more::{Deref, From};
+use sea_orm::{
+    ColumnTrait, Condition, ConnectionTrait, DatabaseConnection, DatabaseTransaction, DbErr,
+    EntityTrait, QueryFilter, QueryOrder, QuerySelect, Set,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// The number of events read from the event log at a time when replaying
+const REPLAY_PAGE_SIZE: u64 = 256;
+
+/// A change to a shipment, or to any dewar, puck or pin beneath it
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ShipmentEvent {
+    pub sequence: u64,
+    pub mutation_type: MutationType,
+    pub proposal_id: u32,
+    pub shipment_id: u32,
+    pub entity: NodeId,
+}
+
+impl ShipmentEvent {
+    /// Locks the event log until the transaction ends. Sequence numbers are assigned on insert,
+    /// so without the lock concurrent writers could commit a later event before an earlier one,
+    /// which a reader replaying from the earlier sequence number would then never see.
+    pub async fn lock_log(transaction: &DatabaseTransaction) -> Result<(), DbErr> {
+        event_lock::Entity::find_by_id(event_lock::LOCK_ID)
+            .lock_exclusive()
+            .one(transaction)
+            .await?;
+        Ok(())
+    }
+
+    /// Appends an event to the durable event log, assigning it the next sequence number. The event
+    /// log should be locked by the transaction beforehand, and the event published only once the
+    /// transaction has been committed.
+    pub async fn record(
+        mutation_type: MutationType,
+        proposal_id: u32,
+        shipment_id: u32,
+        entity: NodeId,
+        database: &impl ConnectionTrait,
+    ) -> Result<Self, DbErr> {
+        let insert = event_log::Entity::insert(event_log::ActiveModel {
+            timestamp: Set(chrono::Utc::now()),
+            mutation_type: Set(mutation_type_name(mutation_type).to_string()),
+            proposal_id: Set(proposal_id),
+            shipment_id: Set(shipment_id),
+            entity_type: Set(entity.node_type.to_string()),
+            entity_id: Set(entity.id),
+            ..Default::default()
+        })
+        .exec(database)
+        .await?;
+        Ok(Self {
+            sequence: insert.last_insert_id,
+            mutation_type,
+            proposal_id,
+            shipment_id,
+            entity,
+        })
+    }
+
+    /// Finds which of the entities already have a creation event in the event log. The event log
+    /// should be locked by the transaction beforehand, such that creations committed by concurrent
+    /// writers are seen.
+    pub async fn recorded_creations(
+        entities: impl IntoIterator<Item = NodeId>,
+        database: &impl ConnectionTrait,
+    ) -> Result<HashSet<NodeId>, DbErr> {
+        let mut ids_by_type = HashMap::<NodeType, Vec<u32>>::new();
+        for entity in entities {
+            ids_by_type
+                .entry(entity.node_type)
+                .or_default()
+                .push(entity.id);
+        }
+        if ids_by_type.is_empty() {
+            return Ok(HashSet::new());
+        }
+        let entities =
+            ids_by_type
+                .into_iter()
+                .fold(Condition::any(), |condition, (node_type, ids)| {
+                    condition.add(
+                        Condition::all()
+                            .add(event_log::Column::EntityType.eq(node_type.to_string()))
+                            .add(event_log::Column::EntityId.is_in(ids)),
+                    )
+                });
+        event_log::Entity::find()
+            .filter(event_log::Column::MutationType.eq(mutation_type_name(MutationType::Created)))
+            .filter(entities)
+            .all(database)
+            .await?
+            .into_iter()
+            .map(|event| Self::try_from(event).map(|event| event.entity))
+            .collect()
+    }
+
+    /// Reads at most `limit` events recorded after the given sequence number, in order
+    pub async fn replay(
+        since: u64,
+        limit: u64,
+        database: &impl ConnectionTrait,
+    ) -> Result<Vec<Self>, DbErr> {
+        event_log::Entity::find()
+            .filter(event_log::Column::Sequence.gt(since))
+            .order_by_asc(event_log::Column::Sequence)
+            .limit(limit)
+            .all(database)
+            .await?
+            .into_iter()
+            .map(Self::try_from)
+            .collect()
+    }
+
+    /// Streams every event recorded after the given sequence number, in order, reading the event
+    /// log a page at a time
+    pub fn replay_all(
+        since: u64,
+        database: DatabaseConnection,
+    ) -> impl Stream<Item = Result<Self, DbErr>> {
+        stream::try_unfold(Some(since), move |since| {
+            let database = database.clone();
+            async move {
+                let Some(since) = since else {
+                    return Ok(None);
+                };
+                let events = Self::replay(since, REPLAY_PAGE_SIZE, &database).await?;
+                let next = match events.last() {
+                    Some(last) if events.len() as u64 == REPLAY_PAGE_SIZE => Some(last.sequence),
+                    _ => None,
+                };
+                Ok(Some((stream::iter(events.into_iter().map(Ok)), next)))
+            }
+        })
+        .try_flatten()
+    }
+}
+
+fn mutation_type_name(mutation_type: MutationType) -> &'static str {
+    match mutation_type {
+        MutationType::Created => "Created",
+        MutationType::Updated => "Updated",
+        MutationType::Deleted => "Deleted",
+    }
+}
+
+impl TryFrom<event_log::Model> for ShipmentEvent {
+    type Error = DbErr;
+
+    fn try_from(value: event_log::Model) -> Result<Self, Self::Error> {
+        let mutation_type = match value.mutation_type.as_str() {
+            "Created" => MutationType::Created,
+            "Updated" => MutationType::Updated,
+            "Deleted" => MutationType::Deleted,
+            mutation_type => {
+                return Err(DbErr::Custom(format!(
+                    "Unknown mutation type '{}' in event {}",
+                    mutation_type, value.sequence
+                )))
+            }
+        };
+        let node_type = value.entity_type.parse().map_err(|_| {
+            DbErr::Custom(format!(
+                "Unknown entity type '{}' in event {}",
+                value.entity_type, value.sequence
+            ))
+        })?;
+        Ok(Self {
+            sequence: value.sequence,
+            mutation_type,
+            proposal_id: value.proposal_id,
+            shipment_id: value.shipment_id,
+            entity: NodeId::new(node_type, value.entity_id),
+        })
+    }
+}
+
+#[derive(Debug, Clone, From, Deref)]
+pub struct ShipmentChanged(ShipmentEvent);
+
+#[Object]
+impl ShipmentChanged {
+    /// The position of this event in the event log, which may be used to resume a subscription
+    async fn sequence(&self) -> &u64 {
+        &self.sequence
+    }
+
+    async fn mutation_type(&self) -> MutationType {
+        self.mutation_type
+    }
+
+    async fn proposal_id(&self) -> &u32 {
+        &self.proposal_id
+    }
+
+    async fn shipment_id(&self) -> &u32 {
+        &self.shipment_id
+    }
+
+    async fn entity_id(&self) -> ID {
+        self.entity.into()
+    }
+
+    /// The changed entity, as currently stored, or null if it has since been deleted
+    async fn entity(&self, ctx: &Context<'_>) -> async_graphql::Result<Option<Node>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        let access = ProposalAccess::of_caller(ctx).await?;
+        Ok(Node::fetch(self.entity, &access, database).await?)
+    }
+}
@@ -0,0 +1,110 @@
+use super::authorisation::StaffGuard;
+use crate::tables::webhook_delivery;
+use async_graphql::{Context, Enum, Object};
+use chrono::{DateTime, Utc};
+use derive_more::{Deref, DerefMut, From};
+use sea_orm::{ColumnTrait, DatabaseConnection, EntityTrait, QueryFilter, QueryOrder, QueryTrait};
+
+#[derive(Debug, Enum, Clone, Copy, PartialEq, Eq)]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    Failed,
+}
+
+impl From<webhook_delivery::DeliveryStatus> for DeliveryStatus {
+    fn from(value: webhook_delivery::DeliveryStatus) -> Self {
+        match value {
+            webhook_delivery::DeliveryStatus::Pending => Self::Pending,
+            webhook_delivery::DeliveryStatus::Delivered => Self::Delivered,
+            webhook_delivery::DeliveryStatus::Failed => Self::Failed,
+        }
+    }
+}
+
+impl From<DeliveryStatus> for webhook_delivery::DeliveryStatus {
+    fn from(value: DeliveryStatus) -> Self {
+        match value {
+            DeliveryStatus::Pending => Self::Pending,
+            DeliveryStatus::Delivered => Self::Delivered,
+            DeliveryStatus::Failed => Self::Failed,
+        }
+    }
+}
+
+#[derive(Debug, Clone, From, Deref, DerefMut)]
+pub struct WebhookDelivery(webhook_delivery::Model);
+
+#[Object]
+impl WebhookDelivery {
+    async fn id(&self) -> &u64 {
+        &self.id
+    }
+
+    async fn url(&self) -> &String {
+        &self.url
+    }
+
+    async fn event_sequence(&self) -> &u64 {
+        &self.event_sequence
+    }
+
+    async fn payload(&self) -> &String {
+        &self.payload
+    }
+
+    async fn status(&self) -> DeliveryStatus {
+        self.status.into()
+    }
+
+    async fn attempts(&self) -> &u32 {
+        &self.attempts
+    }
+
+    async fn created_at(&self) -> &DateTime<Utc> {
+        &self.created_at
+    }
+
+    async fn next_attempt_at(&self) -> &DateTime<Utc> {
+        &self.next_attempt_at
+    }
+
+    async fn delivered_at(&self) -> &Option<DateTime<Utc>> {
+        &self.delivered_at
+    }
+
+    async fn last_error(&self) -> &Option<String> {
+        &self.last_error
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct WebhookQuery;
+
+#[Object]
+impl WebhookQuery {
+    #[graphql(guard = "StaffGuard")]
+    async fn webhook_deliveries(
+        &self,
+        ctx: &Context<'_>,
+        status: Option<DeliveryStatus>,
+        event_sequence: Option<u64>,
+    ) -> async_graphql::Result<Vec<WebhookDelivery>> {
+        let database = ctx.data::<DatabaseConnection>()?;
+        webhook_delivery::Entity::find()
+            .apply_if(status, |query, status| {
+                query.filter(
+                    webhook_delivery::Column::Status
+                        .eq(webhook_delivery::DeliveryStatus::from(status)),
+                )
+            })
+            .apply_if(event_sequence, |query, event_sequence| {
+                query.filter(webhook_delivery::Column::EventSequence.eq(event_sequence))
+            })
+            .order_by_asc(webhook_delivery::Column::Id)
+            .all(database)
+            .await
+            .map(|deliveries| deliveries.into_iter().map(WebhookDelivery::from).collect())
+            .map_err(async_graphql::Error::from)
+    }
+}
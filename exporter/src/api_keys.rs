@@ -0,0 +1,94 @@
+use crate::{
+    auth::{Scope, ServiceAccount},
+    tables::api_key,
+};
+use sea_orm::{
+    ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, QueryOrder,
+    Set,
+};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// The prefix which distinguishes API keys from OIDC access tokens
+pub const API_KEY_PREFIX: &str = "xcl_";
+
+fn hash_key(key: &str) -> String {
+    hex::encode(Sha256::digest(key.as_bytes()))
+}
+
+fn parse_scopes(scopes: &str) -> Vec<Scope> {
+    scopes
+        .split(',')
+        .filter_map(|scope| scope.parse().ok())
+        .collect()
+}
+
+impl From<api_key::Model> for ServiceAccount {
+    fn from(value: api_key::Model) -> Self {
+        Self {
+            id: value.id,
+            name: value.name,
+            scopes: parse_scopes(&value.scopes),
+        }
+    }
+}
+
+/// Creates an API key with the given scopes, returning the key itself, which is only stored hashed
+pub async fn create(
+    name: String,
+    scopes: &[Scope],
+    database: &DatabaseConnection,
+) -> Result<(api_key::Model, String), DbErr> {
+    let key = format!(
+        "{}{}{}",
+        API_KEY_PREFIX,
+        Uuid::new_v4().simple(),
+        Uuid::new_v4().simple()
+    );
+    let model = api_key::ActiveModel {
+        name: Set(name),
+        key_hash: Set(hash_key(&key)),
+        scopes: Set(scopes
+            .iter()
+            .map(Scope::as_str)
+            .collect::<Vec<_>>()
+            .join(",")),
+        created_at: Set(chrono::Utc::now()),
+        revoked_at: Set(None),
+        ..Default::default()
+    }
+    .insert(database)
+    .await?;
+    Ok((model, key))
+}
+
+pub async fn list(database: &DatabaseConnection) -> Result<Vec<api_key::Model>, DbErr> {
+    api_key::Entity::find()
+        .order_by_asc(api_key::Column::Id)
+        .all(database)
+        .await
+}
+
+/// Revokes an API key, returning false if no such key exists
+pub async fn revoke(id: u32, database: &DatabaseConnection) -> Result<bool, DbErr> {
+    let Some(model) = api_key::Entity::find_by_id(id).one(database).await? else {
+        return Ok(false);
+    };
+    let mut update = api_key::ActiveModel::from(model);
+    update.revoked_at = Set(Some(chrono::Utc::now()));
+    update.update(database).await?;
+    Ok(true)
+}
+
+/// Finds the service account holding an unrevoked API key
+pub async fn authenticate(
+    key: &str,
+    database: &DatabaseConnection,
+) -> Result<Option<ServiceAccount>, DbErr> {
+    Ok(api_key::Entity::find()
+        .filter(api_key::Column::KeyHash.eq(hash_key(key)))
+        .filter(api_key::Column::RevokedAt.is_null())
+        .one(database)
+        .await?
+        .map(ServiceAccount::from))
+}
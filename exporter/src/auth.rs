@@ -0,0 +1,536 @@
+use crate::api_keys::{self, API_KEY_PREFIX};
+use clap::ValueEnum;
+use derive_more::{Display, Error, From};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use sea_orm::{DatabaseConnection, DbErr};
+use serde::Deserialize;
+use std::{
+    str::FromStr,
+    sync::RwLock,
+    time::{Duration, Instant},
+};
+use tokio::sync::Mutex;
+
+/// The prefix of a bearer token in an `Authorization` header or websocket `connection_init` payload
+const BEARER_PREFIX: &str = "Bearer ";
+
+/// The shortest interval between fetches of the JWKS prompted by tokens signed with an unknown key,
+/// such that a flood of forged tokens cannot be used to flood the identity provider
+const JWKS_REFETCH_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The age after which the JWKS is fetched again, such that keys withdrawn by the identity
+/// provider stop being accepted
+const JWKS_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+/// The identity of an authenticated caller, made available to resolvers via the `Context`
+#[derive(Debug, Clone)]
+pub enum Caller {
+    /// A human user, authenticated with an OIDC access token
+    User(User),
+    /// A service, authenticated with an API key
+    Service(ServiceAccount),
+}
+
+#[derive(Debug, Clone)]
+pub struct User {
+    /// The subject of the token, as assigned by the identity provider
+    pub subject: String,
+    /// The FedID of the caller, matching `Person.login` in ISPyB
+    pub login: Option<String>,
+    /// Whether the caller holds the staff role, granting access to every proposal
+    pub staff: bool,
+}
+
+/// An operation an API key may be permitted to perform
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Scope {
+    /// Permits all queries and subscriptions
+    #[value(name = "read-only")]
+    ReadOnly,
+    /// Permits shipments to be created, along with all queries and subscriptions
+    #[value(name = "shipment:write")]
+    ShipmentWrite,
+}
+
+impl Scope {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::ReadOnly => "read-only",
+            Self::ShipmentWrite => "shipment:write",
+        }
+    }
+}
+
+impl FromStr for Scope {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "read-only" => Ok(Self::ReadOnly),
+            "shipment:write" => Ok(Self::ShipmentWrite),
+            _ => Err(format!("Unknown scope '{}'", s)),
+        }
+    }
+}
+
+/// A service holding an API key. API keys are not restricted to particular proposals, so a key
+/// holding any scope may access every proposal.
+#[derive(Debug, Clone)]
+pub struct ServiceAccount {
+    /// The id of the API key
+    pub id: u32,
+    /// The name given to the API key when it was created
+    pub name: String,
+    /// The operations the API key permits
+    pub scopes: Vec<Scope>,
+}
+
+impl Caller {
+    /// A description of the caller suitable for the audit log
+    pub fn identity(&self) -> String {
+        match self {
+            Self::User(user) => match &user.login {
+                Some(login) => format!("user:{}", login),
+                None => format!("user:{}", user.subject),
+            },
+            Self::Service(service) => format!("service:{}#{}", service.name, service.id),
+        }
+    }
+
+    /// Whether the caller may perform operations requiring the given scope, which users always may
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        match self {
+            Self::User(_) => true,
+            Self::Service(service) => service.scopes.contains(&scope),
+        }
+    }
+
+    /// Whether the caller may query and subscribe, which requires either scope of an API key
+    pub fn may_read(&self) -> bool {
+        self.has_scope(Scope::ReadOnly) || self.has_scope(Scope::ShipmentWrite)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Claims {
+    sub: String,
+    #[serde(default)]
+    preferred_username: Option<String>,
+    #[serde(default)]
+    roles: Vec<String>,
+}
+
+#[derive(Debug, Display, Error, From)]
+pub enum AuthError {
+    #[display(fmt = "No bearer token was supplied")]
+    #[from(ignore)]
+    MissingToken,
+    #[display(fmt = "The token was not signed by a known key")]
+    #[from(ignore)]
+    UnknownKey,
+    #[display(fmt = "The token is invalid: {}", _0)]
+    InvalidToken(jsonwebtoken::errors::Error),
+    #[display(fmt = "The API key is unknown or has been revoked")]
+    #[from(ignore)]
+    UnknownApiKey,
+    #[display(fmt = "Could not look up API key: {}", _0)]
+    Database(DbErr),
+}
+
+#[derive(Debug, Display, Error, From)]
+pub enum JwksError {
+    #[display(fmt = "Could not fetch JWKS: {}", _0)]
+    Fetch(reqwest::Error),
+    #[display(fmt = "Could not read JWKS: {}", _0)]
+    Read(std::io::Error),
+    #[display(fmt = "Could not parse JWKS: {}", _0)]
+    Parse(serde_json::Error),
+}
+
+/// Lists the algorithms with which tokens verified by the key may be signed: that specified by the
+/// key, or otherwise every algorithm for its type of key. The algorithm is never taken from the
+/// token itself, which could otherwise choose one the key was not intended for.
+fn permitted_algorithms(jwk: &Jwk) -> Vec<Algorithm> {
+    if let Some(algorithm) = jwk.common.algorithm {
+        return vec![algorithm];
+    }
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => vec![
+            Algorithm::RS256,
+            Algorithm::RS384,
+            Algorithm::RS512,
+            Algorithm::PS256,
+            Algorithm::PS384,
+            Algorithm::PS512,
+        ],
+        AlgorithmParameters::EllipticCurve(parameters) => match parameters.curve {
+            EllipticCurve::P256 => vec![Algorithm::ES256],
+            EllipticCurve::P384 => vec![Algorithm::ES384],
+            EllipticCurve::Ed25519 => vec![Algorithm::EdDSA],
+            EllipticCurve::P521 => Vec::new(),
+        },
+        AlgorithmParameters::OctetKeyPair(_) => vec![Algorithm::EdDSA],
+        // Symmetric keys are shared with every client of the identity provider
+        AlgorithmParameters::OctetKey(_) => Vec::new(),
+    }
+}
+
+/// Fetches a JWKS from `source`, which may be either a HTTP(S) URL or a local file path
+async fn fetch_jwks(source: &str) -> Result<JwkSet, JwksError> {
+    if source.starts_with("http://") || source.starts_with("https://") {
+        Ok(reqwest::get(source)
+            .await?
+            .error_for_status()?
+            .json()
+            .await?)
+    } else {
+        Ok(serde_json::from_str(
+            &tokio::fs::read_to_string(source).await?,
+        )?)
+    }
+}
+
+/// The most recently fetched JWKS
+#[derive(Debug)]
+struct Keys {
+    jwks: JwkSet,
+    /// When the JWKS was last fetched, or last failed to be fetched
+    checked_at: Instant,
+}
+
+impl Keys {
+    /// Finds the key with the given id. A token without a key id is only accepted if the key set
+    /// holds a single key.
+    fn find(&self, kid: Option<&str>) -> Option<&Jwk> {
+        match kid {
+            Some(kid) => self.jwks.find(kid),
+            None if self.jwks.keys.len() == 1 => self.jwks.keys.first(),
+            None => None,
+        }
+    }
+}
+
+/// Validates OIDC access tokens against a JSON Web Key Set, and API keys against the database. The
+/// JWKS is fetched again when a token is signed by an unknown key, so that keys rotated in by the
+/// identity provider are accepted, and once it has grown stale, so that keys rotated out are not.
+#[derive(Debug)]
+pub struct Authenticator {
+    database: DatabaseConnection,
+    source: String,
+    keys: RwLock<Keys>,
+    /// Held while the JWKS is fetched, such that concurrent requests fetch it only once
+    refetch: Mutex<()>,
+    issuer: String,
+    audience: String,
+    staff_role: Option<String>,
+}
+
+impl Authenticator {
+    /// Loads the JWKS from `source`, which may be either a HTTP(S) URL or a local file path
+    pub async fn load(
+        database: DatabaseConnection,
+        source: &str,
+        issuer: String,
+        audience: String,
+        staff_role: Option<String>,
+    ) -> Result<Self, JwksError> {
+        Ok(Self {
+            database,
+            source: source.to_string(),
+            keys: RwLock::new(Keys {
+                jwks: fetch_jwks(source).await?,
+                checked_at: Instant::now(),
+            }),
+            refetch: Mutex::new(()),
+            issuer,
+            audience,
+            staff_role,
+        })
+    }
+
+    /// Finds the key with which a token was signed, fetching the JWKS again if the key is unknown
+    /// or the JWKS is stale. Should the fetch fail the keys previously fetched continue to be used.
+    async fn find_key(&self, kid: Option<&str>) -> Result<Jwk, AuthError> {
+        let (jwk, checked_at) = {
+            let keys = self.keys.read().unwrap();
+            (keys.find(kid).cloned(), keys.checked_at)
+        };
+        let refetch_after = match jwk {
+            Some(_) => JWKS_MAX_AGE,
+            None => JWKS_REFETCH_INTERVAL,
+        };
+        if checked_at.elapsed() < refetch_after {
+            return jwk.ok_or(AuthError::UnknownKey);
+        }
+
+        let _refetch = self.refetch.lock().await;
+        // Another request may have fetched the JWKS while this one waited
+        if self.keys.read().unwrap().checked_at == checked_at {
+            let jwks = fetch_jwks(&self.source).await;
+            let mut keys = self.keys.write().unwrap();
+            keys.checked_at = Instant::now();
+            match jwks {
+                Ok(jwks) => keys.jwks = jwks,
+                Err(err) => tracing::warn!("Could not refresh {}: {}", self.source, err),
+            }
+        }
+        self.keys
+            .read()
+            .unwrap()
+            .find(kid)
+            .cloned()
+            .ok_or(AuthError::UnknownKey)
+    }
+
+    /// Authenticates a token, which may be either an API key or an OIDC access token
+    pub async fn authenticate(&self, token: &str) -> Result<Caller, AuthError> {
+        if token.starts_with(API_KEY_PREFIX) {
+            api_keys::authenticate(token, &self.database)
+                .await?
+                .map(Caller::Service)
+                .ok_or(AuthError::UnknownApiKey)
+        } else {
+            self.authenticate_jwt(token).await.map(Caller::User)
+        }
+    }
+
+    /// Validates the signature, issuer, audience and expiry of a token, returning its bearer
+    async fn authenticate_jwt(&self, token: &str) -> Result<User, AuthError> {
+        let header = decode_header(token)?;
+        let jwk = self.find_key(header.kid.as_deref()).await?;
+        let mut validation = Validation::default();
+        validation.algorithms = permitted_algorithms(&jwk);
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        let claims = decode::<Claims>(token, &DecodingKey::from_jwk(&jwk)?, &validation)?.claims;
+        Ok(User {
+            staff: self
+                .staff_role
+                .as_ref()
+                .map_or(false, |staff_role| claims.roles.contains(staff_role)),
+            subject: claims.sub,
+            login: claims.preferred_username,
+        })
+    }
+
+    /// Authenticates the bearer token in the `Authorization` entry of a `connection_init` payload
+    pub async fn authenticate_connection(
+        &self,
+        payload: &serde_json::Value,
+    ) -> Result<Caller, AuthError> {
+        let authorization = payload
+            .get("Authorization")
+            .or_else(|| payload.get("authorization"))
+            .and_then(serde_json::Value::as_str)
+            .ok_or(AuthError::MissingToken)?;
+        let token = authorization
+            .strip_prefix(BEARER_PREFIX)
+            .unwrap_or(authorization);
+        self.authenticate(token).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AuthError, Authenticator, JWKS_MAX_AGE, JWKS_REFETCH_INTERVAL};
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+    use sea_orm::DatabaseConnection;
+    use serde_json::json;
+    use std::{
+        path::{Path, PathBuf},
+        time::{Duration, Instant},
+    };
+
+    const ISSUER: &str = "https://identity.example.com";
+    const AUDIENCE: &str = "xchemlab";
+    const SIGNING_KEY: &[u8] = include_bytes!("../test-data/jwt-signing-key.pem");
+
+    fn test_data(file: &str) -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test-data")
+            .join(file)
+    }
+
+    async fn authenticator(jwks: &str) -> Authenticator {
+        load_authenticator(&test_data(jwks)).await
+    }
+
+    async fn load_authenticator(jwks: &Path) -> Authenticator {
+        Authenticator::load(
+            DatabaseConnection::Disconnected,
+            jwks.to_str().unwrap(),
+            ISSUER.to_string(),
+            AUDIENCE.to_string(),
+            Some("staff".to_string()),
+        )
+        .await
+        .unwrap()
+    }
+
+    /// Copies a key set from the test data to a file of its own, which a test may then replace
+    fn copy_jwks(jwks: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("jwks-{}.json", uuid::Uuid::new_v4()));
+        std::fs::copy(test_data(jwks), &path).unwrap();
+        path
+    }
+
+    /// Pretends the key set was fetched the given time ago
+    fn age_keys(authenticator: &Authenticator, age: Duration) {
+        authenticator.keys.write().unwrap().checked_at = Instant::now().checked_sub(age).unwrap();
+    }
+
+    fn token(kid: Option<&str>, audience: &str, expires_in: i64) -> String {
+        let mut header = Header::new(Algorithm::RS256);
+        header.kid = kid.map(str::to_string);
+        let claims = json!({
+            "sub": "abc123",
+            "preferred_username": "abc12345",
+            "roles": ["staff"],
+            "iss": ISSUER,
+            "aud": audience,
+            "exp": chrono::Utc::now().timestamp() + expires_in,
+        });
+        encode(
+            &header,
+            &claims,
+            &EncodingKey::from_rsa_pem(SIGNING_KEY).unwrap(),
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn accepts_valid_token() {
+        let authenticator = authenticator("jwks.json").await;
+        let user = authenticator
+            .authenticate_jwt(&token(Some("test-key"), AUDIENCE, 300))
+            .await
+            .unwrap();
+        assert_eq!(user.subject, "abc123");
+        assert_eq!(user.login.as_deref(), Some("abc12345"));
+        assert!(user.staff);
+    }
+
+    #[tokio::test]
+    async fn rejects_expired_token() {
+        let authenticator = authenticator("jwks.json").await;
+        let result = authenticator
+            .authenticate_jwt(&token(Some("test-key"), AUDIENCE, -300))
+            .await;
+        assert!(matches!(result, Err(AuthError::InvalidToken(_))));
+    }
+
+    #[tokio::test]
+    async fn rejects_wrong_audience() {
+        let authenticator = authenticator("jwks.json").await;
+        let result = authenticator
+            .authenticate_jwt(&token(Some("test-key"), "elsewhere", 300))
+            .await;
+        assert!(matches!(result, Err(AuthError::InvalidToken(_))));
+    }
+
+    #[tokio::test]
+    async fn rejects_unknown_kid() {
+        let authenticator = authenticator("jwks.json").await;
+        let result = authenticator
+            .authenticate_jwt(&token(Some("other-key"), AUDIENCE, 300))
+            .await;
+        assert!(matches!(result, Err(AuthError::UnknownKey)));
+    }
+
+    #[tokio::test]
+    async fn rejects_algorithm_chosen_by_token() {
+        let authenticator = authenticator("jwks.json").await;
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("test-key".to_string());
+        let claims = json!({
+            "sub": "abc123",
+            "iss": ISSUER,
+            "aud": AUDIENCE,
+            "exp": chrono::Utc::now().timestamp() + 300,
+        });
+        let token = encode(&header, &claims, &EncodingKey::from_secret(b"secret")).unwrap();
+        let result = authenticator.authenticate_jwt(&token).await;
+        assert!(matches!(result, Err(AuthError::InvalidToken(_))));
+    }
+
+    #[tokio::test]
+    async fn accepts_missing_kid_with_single_key() {
+        let authenticator = authenticator("jwks.json").await;
+        assert!(authenticator
+            .authenticate_jwt(&token(None, AUDIENCE, 300))
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn rejects_missing_kid_with_multiple_keys() {
+        let authenticator = authenticator("jwks-rotating.json").await;
+        assert!(authenticator
+            .authenticate_jwt(&token(Some("rotated-key"), AUDIENCE, 300))
+            .await
+            .is_ok());
+        let result = authenticator
+            .authenticate_jwt(&token(None, AUDIENCE, 300))
+            .await;
+        assert!(matches!(result, Err(AuthError::UnknownKey)));
+    }
+
+    #[tokio::test]
+    async fn fetches_the_key_set_again_for_an_unknown_kid() {
+        let jwks = copy_jwks("jwks.json");
+        let authenticator = load_authenticator(&jwks).await;
+        std::fs::copy(test_data("jwks-rotating.json"), &jwks).unwrap();
+        age_keys(&authenticator, JWKS_REFETCH_INTERVAL);
+        let result = authenticator
+            .authenticate_jwt(&token(Some("rotated-key"), AUDIENCE, 300))
+            .await;
+        std::fs::remove_file(jwks).unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn limits_how_often_the_key_set_is_fetched_again() {
+        let jwks = copy_jwks("jwks.json");
+        let authenticator = load_authenticator(&jwks).await;
+        std::fs::copy(test_data("jwks-rotating.json"), &jwks).unwrap();
+        let result = authenticator
+            .authenticate_jwt(&token(Some("rotated-key"), AUDIENCE, 300))
+            .await;
+        std::fs::remove_file(jwks).unwrap();
+        assert!(matches!(result, Err(AuthError::UnknownKey)));
+    }
+
+    #[tokio::test]
+    async fn stops_accepting_withdrawn_keys_once_the_key_set_is_stale() {
+        let jwks = copy_jwks("jwks.json");
+        let authenticator = load_authenticator(&jwks).await;
+        std::fs::write(&jwks, r#"{"keys": []}"#).unwrap();
+        age_keys(&authenticator, JWKS_REFETCH_INTERVAL);
+        let fresh = authenticator
+            .authenticate_jwt(&token(Some("test-key"), AUDIENCE, 300))
+            .await;
+        age_keys(&authenticator, JWKS_MAX_AGE);
+        let stale = authenticator
+            .authenticate_jwt(&token(Some("test-key"), AUDIENCE, 300))
+            .await;
+        std::fs::remove_file(jwks).unwrap();
+        assert!(fresh.is_ok());
+        assert!(matches!(stale, Err(AuthError::UnknownKey)));
+    }
+
+    #[tokio::test]
+    async fn keeps_the_keys_when_the_key_set_cannot_be_fetched_again() {
+        let jwks = copy_jwks("jwks.json");
+        let authenticator = load_authenticator(&jwks).await;
+        std::fs::remove_file(&jwks).unwrap();
+        age_keys(&authenticator, JWKS_MAX_AGE);
+        assert!(authenticator
+            .authenticate_jwt(&token(Some("test-key"), AUDIENCE, 300))
+            .await
+            .is_ok());
+    }
+}
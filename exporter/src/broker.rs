@@ -1,21 +1,392 @@
-use async_graphql::futures_util::Stream;
-use once_cell::sync::Lazy;
-use tokio::sync::broadcast::{channel, Sender};
+use crate::metrics;
+use async_graphql::{
+    futures_util::{future::BoxFuture, stream::BoxStream, FutureExt, StreamExt},
+    ErrorExtensions,
+};
+use redis::{
+    aio::{ConnectionManager, PubSub},
+    AsyncCommands, RedisResult,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::{
+    broadcast::{channel, Sender},
+    mpsc, oneshot,
+};
 use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
 
+/// A publish-subscribe channel through which events are delivered to subscribers
+pub trait EventBroker<E>: Send + Sync {
+    fn publish(&self, event: E);
+
+    fn subscribe(&self) -> BoxStream<'static, Result<E, BroadcastStreamRecvError>>;
+
+    /// Whether events published now will be delivered to subscribers
+    fn is_ready(&self) -> bool {
+        true
+    }
+
+    /// Waits until every event published so far has been handed on, such as before exiting
+    fn flush(&self) -> BoxFuture<'static, ()> {
+        async {}.boxed()
+    }
+}
+
+/// An [`EventBroker`] shared between the API and background tasks
+pub type SharedEventBroker<E> = Arc<dyn EventBroker<E>>;
+
+/// An [`EventBroker`] which delivers events to subscribers within this process only
 #[derive(Debug)]
-pub struct EventBroker<E: Clone>(Lazy<Sender<E>>);
+pub struct InMemoryEventBroker<E: Clone>(Sender<E>);
 
-impl<E: std::fmt::Debug + Send + Sync + Clone + 'static> EventBroker<E> {
-    pub const fn new() -> Self {
-        Self(Lazy::new(|| channel::<E>(1024).0))
+impl<E: std::fmt::Debug + Send + Sync + Clone + 'static> InMemoryEventBroker<E> {
+    /// Creates a broker which retains up to `capacity` events for each subscriber before they lag
+    pub fn new(capacity: usize) -> Self {
+        Self(channel::<E>(capacity).0)
     }
+}
 
-    pub fn publish(&self, event: E) {
+impl<E: std::fmt::Debug + Send + Sync + Clone + 'static> EventBroker<E> for InMemoryEventBroker<E> {
+    fn publish(&self, event: E) {
         let _ = self.0.send(event);
     }
 
-    pub fn subscribe(&self) -> impl Stream<Item = Result<E, BroadcastStreamRecvError>> {
+    fn subscribe(&self) -> BoxStream<'static, Result<E, BroadcastStreamRecvError>> {
         BroadcastStream::new(self.0.subscribe())
+            .inspect(|event| {
+                if let Err(BroadcastStreamRecvError::Lagged(missed)) = event {
+                    metrics::record_missed_events(*missed);
+                }
+            })
+            .boxed()
+    }
+}
+
+/// The delay before the first attempt to reconnect to, or republish through, Redis
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+
+/// The longest delay between successive attempts to reconnect to, or republish through, Redis
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// A message to the task publishing events to Redis
+enum Outgoing {
+    /// A serialized event to publish
+    Event(String),
+    /// A request to be notified once every previously queued event has been published
+    Flush(oneshot::Sender<()>),
+}
+
+/// An [`EventBroker`] which fans events out to every replica subscribed to a Redis pub/sub channel
+pub struct RedisEventBroker<E: Clone> {
+    publisher: mpsc::Sender<Outgoing>,
+    local: InMemoryEventBroker<E>,
+    subscribed: Arc<AtomicBool>,
+}
+
+impl<E> RedisEventBroker<E>
+where
+    E: std::fmt::Debug + Send + Sync + Clone + Serialize + DeserializeOwned + 'static,
+{
+    /// Connects to the Redis instance at `url`, relaying events received on `channel_name` to a
+    /// local broker which retains up to `capacity` events for each subscriber before they lag.
+    /// Up to `capacity` events are queued for publishing while Redis is unreachable.
+    pub async fn connect(url: &str, channel_name: String, capacity: usize) -> RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        let publisher = ConnectionManager::new(client.clone()).await?;
+        let subscriber = subscribe_to(&client, &channel_name).await?;
+
+        let (queue, outgoing) = mpsc::channel(capacity);
+        tokio::spawn(publish_events(publisher, channel_name.clone(), outgoing));
+
+        let local = InMemoryEventBroker::new(capacity);
+        let subscribed = Arc::new(AtomicBool::new(true));
+        tokio::spawn(relay_events(
+            client,
+            channel_name,
+            subscriber,
+            local.0.clone(),
+            subscribed.clone(),
+        ));
+
+        Ok(Self {
+            publisher: queue,
+            local,
+            subscribed,
+        })
+    }
+}
+
+async fn subscribe_to(client: &redis::Client, channel_name: &str) -> RedisResult<PubSub> {
+    let mut subscriber = client.get_async_connection().await?.into_pubsub();
+    subscriber.subscribe(channel_name).await?;
+    Ok(subscriber)
+}
+
+/// Publishes queued events one at a time, such that they reach Redis in the order they were
+/// published, retrying each with exponential backoff until it succeeds
+async fn publish_events(
+    mut publisher: ConnectionManager,
+    channel_name: String,
+    mut outgoing: mpsc::Receiver<Outgoing>,
+) {
+    while let Some(message) = outgoing.recv().await {
+        let payload = match message {
+            Outgoing::Event(payload) => payload,
+            Outgoing::Flush(flushed) => {
+                let _ = flushed.send(());
+                continue;
+            }
+        };
+        let mut backoff = INITIAL_BACKOFF;
+        while let Err(err) = publisher.publish::<_, _, ()>(&channel_name, &payload).await {
+            tracing::warn!("Could not publish event to Redis, retrying: {}", err);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_BACKOFF);
+        }
+    }
+}
+
+/// Relays events received from Redis to local subscribers, resubscribing with exponential backoff
+/// whenever the connection is lost. Events published while disconnected are not received, but may
+/// be replayed from the event log.
+async fn relay_events<E: DeserializeOwned>(
+    client: redis::Client,
+    channel_name: String,
+    mut subscriber: PubSub,
+    relay: Sender<E>,
+    subscribed: Arc<AtomicBool>,
+) {
+    loop {
+        subscribed.store(true, Ordering::Relaxed);
+        let mut messages = Box::pin(subscriber.into_on_message());
+        while let Some(message) = messages.next().await {
+            match message
+                .get_payload::<String>()
+                .map_err(|err| err.to_string())
+                .and_then(|payload| {
+                    serde_json::from_str::<E>(&payload).map_err(|err| err.to_string())
+                }) {
+                Ok(event) => {
+                    let _ = relay.send(event);
+                }
+                Err(err) => tracing::warn!("Discarding malformed event from Redis: {}", err),
+            }
+        }
+        subscribed.store(false, Ordering::Relaxed);
+        tracing::warn!("Lost connection to the Redis event channel, reconnecting");
+
+        let mut backoff = INITIAL_BACKOFF;
+        subscriber = loop {
+            tokio::time::sleep(backoff).await;
+            match subscribe_to(&client, &channel_name).await {
+                Ok(subscriber) => break subscriber,
+                Err(err) => {
+                    tracing::warn!("Could not reconnect to the Redis event channel: {}", err);
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        };
+    }
+}
+
+impl<E> EventBroker<E> for RedisEventBroker<E>
+where
+    E: std::fmt::Debug + Send + Sync + Clone + Serialize + DeserializeOwned + 'static,
+{
+    fn publish(&self, event: E) {
+        let payload = match serde_json::to_string(&event) {
+            Ok(payload) => payload,
+            Err(err) => {
+                tracing::error!("Could not serialize event {:?}: {}", event, err);
+                return;
+            }
+        };
+        if let Err(err) = self.publisher.try_send(Outgoing::Event(payload)) {
+            tracing::error!("Could not queue event {:?} for Redis: {}", event, err);
+        }
+    }
+
+    fn subscribe(&self) -> BoxStream<'static, Result<E, BroadcastStreamRecvError>> {
+        self.local.subscribe()
+    }
+
+    fn is_ready(&self) -> bool {
+        self.subscribed.load(Ordering::Relaxed)
+    }
+
+    fn flush(&self) -> BoxFuture<'static, ()> {
+        let publisher = self.publisher.clone();
+        async move {
+            let (flushed, published) = oneshot::channel();
+            if publisher.send(Outgoing::Flush(flushed)).await.is_ok() {
+                let _ = published.await;
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Reports that a subscriber fell behind the broker and missed events, such that it should refetch
+pub fn lagged_error(missed: u64) -> async_graphql::Error {
+    async_graphql::Error::new(format!(
+        "Subscriber lagged behind and missed {} events",
+        missed
+    ))
+    .extend_with(|_, extensions| {
+        extensions.set("code", "LAGGED");
+        extensions.set("missed", missed);
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{EventBroker, InMemoryEventBroker, RedisEventBroker, SharedEventBroker};
+    use async_graphql::futures_util::{stream::BoxStream, StreamExt};
+    use redis::AsyncCommands;
+    use std::{sync::Arc, time::Duration};
+    use tokio_stream::wrappers::errors::BroadcastStreamRecvError;
+
+    /// The longest the Redis tests wait for an event to be relayed back from Redis
+    const RELAY_TIMEOUT: Duration = Duration::from_secs(5);
+
+    fn broker(capacity: usize) -> SharedEventBroker<u32> {
+        Arc::new(InMemoryEventBroker::new(capacity))
+    }
+
+    #[tokio::test]
+    async fn delivers_events_in_order_to_every_subscriber() {
+        let broker = broker(8);
+        let mut first = broker.subscribe();
+        let mut second = broker.subscribe();
+        for event in 1..=3 {
+            broker.publish(event);
+        }
+        for subscriber in [&mut first, &mut second] {
+            let events = subscriber.take(3).collect::<Vec<_>>().await;
+            assert_eq!(events, vec![Ok(1), Ok(2), Ok(3)]);
+        }
+    }
+
+    #[tokio::test]
+    async fn omits_events_published_before_subscribing() {
+        let broker = broker(8);
+        broker.publish(1);
+        let mut subscriber = broker.subscribe();
+        broker.publish(2);
+        assert_eq!(subscriber.next().await, Some(Ok(2)));
+    }
+
+    #[tokio::test]
+    async fn reports_events_missed_by_lagging_subscribers() {
+        let broker = broker(2);
+        let subscriber = broker.subscribe();
+        for event in 1..=4 {
+            broker.publish(event);
+        }
+        let events = subscriber.take(3).collect::<Vec<_>>().await;
+        assert_eq!(
+            events,
+            vec![Err(BroadcastStreamRecvError::Lagged(2)), Ok(3), Ok(4)]
+        );
+    }
+
+    #[test]
+    fn is_always_ready() {
+        assert!(broker(1).is_ready());
+    }
+
+    /// The Redis instance the [`RedisEventBroker`] tests run against. These tests are ignored
+    /// unless run with `--ignored`, such as by `REDIS_URL=redis://localhost:6379 cargo test --
+    /// --ignored`.
+    fn redis_url() -> String {
+        std::env::var("REDIS_URL")
+            .expect("REDIS_URL must point to a Redis instance to test against")
+    }
+
+    /// A channel of its own for each test, such that concurrent tests do not see each other's events
+    fn channel_name() -> String {
+        format!("xchemlab-exporter-test-{}", uuid::Uuid::new_v4())
+    }
+
+    async fn redis_broker(channel_name: &str, capacity: usize) -> RedisEventBroker<u32> {
+        RedisEventBroker::connect(&redis_url(), channel_name.to_string(), capacity)
+            .await
+            .unwrap()
+    }
+
+    async fn next_event(
+        subscriber: &mut BoxStream<'static, Result<u32, BroadcastStreamRecvError>>,
+    ) -> Result<u32, BroadcastStreamRecvError> {
+        tokio::time::timeout(RELAY_TIMEOUT, subscriber.next())
+            .await
+            .expect("No event was relayed from Redis")
+            .expect("The subscription ended")
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a Redis instance at REDIS_URL"]
+    async fn redis_delivers_events_in_order_to_every_replica() {
+        let channel_name = channel_name();
+        let publisher = redis_broker(&channel_name, 8).await;
+        let replica = redis_broker(&channel_name, 8).await;
+        assert!(publisher.is_ready() && replica.is_ready());
+        let mut subscribers = [publisher.subscribe(), replica.subscribe()];
+        for event in 1..=3 {
+            publisher.publish(event);
+        }
+        publisher.flush().await;
+        for subscriber in subscribers.iter_mut() {
+            for event in 1..=3 {
+                assert_eq!(next_event(subscriber).await, Ok(event));
+            }
+        }
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a Redis instance at REDIS_URL"]
+    async fn redis_discards_malformed_events() {
+        let channel_name = channel_name();
+        let broker = redis_broker(&channel_name, 8).await;
+        let mut subscriber = broker.subscribe();
+        let mut connection = redis::Client::open(redis_url())
+            .unwrap()
+            .get_async_connection()
+            .await
+            .unwrap();
+        connection
+            .publish::<_, _, ()>(&channel_name, "not an event")
+            .await
+            .unwrap();
+        broker.publish(1);
+        broker.flush().await;
+        assert_eq!(next_event(&mut subscriber).await, Ok(1));
+    }
+
+    #[tokio::test]
+    #[ignore = "requires a Redis instance at REDIS_URL"]
+    async fn redis_reports_events_missed_by_lagging_subscribers() {
+        let channel_name = channel_name();
+        let broker = redis_broker(&channel_name, 2).await;
+        let mut lagging = broker.subscribe();
+        let mut keeping_up = broker.subscribe();
+        // Events are queued for Redis up to the capacity too, so wait for each to be published
+        for event in 1..=4 {
+            broker.publish(event);
+            broker.flush().await;
+        }
+        // Once the last event reaches one subscriber every event has been relayed to both
+        while next_event(&mut keeping_up).await != Ok(4) {}
+        assert_eq!(
+            next_event(&mut lagging).await,
+            Err(BroadcastStreamRecvError::Lagged(2))
+        );
+        assert_eq!(next_event(&mut lagging).await, Ok(3));
+        assert_eq!(next_event(&mut lagging).await, Ok(4));
     }
 }
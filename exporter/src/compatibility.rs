@@ -0,0 +1,415 @@
+use clap::ValueEnum;
+use models::compiled_schema::COMPILED_TABLES;
+use sea_orm::{ConnectionTrait, DatabaseConnection, DbErr, Statement};
+use serde::Deserialize;
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+};
+
+/// The version of ispyb-database against which the models are generated
+pub const EXPECTED_SCHEMA_VERSION: &str = "1.36.1";
+
+/// How to react to an ISPyB schema which differs from that the models were generated against
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SchemaCheck {
+    /// Refuse to start if the schema is incompatible
+    Strict,
+    /// Report incompatibilities, but start regardless
+    Warn,
+    /// Do not check the schema
+    Off,
+}
+
+/// Whether to start, given the differences found by a schema check
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SchemaCheckOutcome {
+    /// The schema matches that the models were generated against
+    Start,
+    /// The schema differs, but the differences should be reported rather than refusing to start
+    StartWithWarnings,
+    /// The schema is incompatible and the exporter must not start
+    Refuse,
+}
+
+impl SchemaCheck {
+    /// Decides whether to start given the differences found in the schema
+    pub fn outcome(self, report: &CompatibilityReport) -> SchemaCheckOutcome {
+        match self {
+            Self::Off => SchemaCheckOutcome::Start,
+            Self::Strict if !report.is_compatible() => SchemaCheckOutcome::Refuse,
+            _ if !report.is_empty() => SchemaCheckOutcome::StartWithWarnings,
+            _ => SchemaCheckOutcome::Start,
+        }
+    }
+}
+
+/// The differences between the live ISPyB schema and that the models were generated against
+#[derive(Debug, Default)]
+pub struct CompatibilityReport {
+    /// The version recorded in `AdminVar`, if any
+    pub schema_version: Option<String>,
+    /// Differences which will cause queries or inserts to fail
+    pub incompatibilities: Vec<String>,
+    /// Differences which are not expected to cause failures
+    pub warnings: Vec<String>,
+}
+
+impl CompatibilityReport {
+    pub fn is_compatible(&self) -> bool {
+        self.incompatibilities.is_empty()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.incompatibilities.is_empty() && self.warnings.is_empty()
+    }
+
+    /// Warns of a schema version other than that the models were generated against
+    fn compare_schema_version(&mut self, schema_version: Option<String>) {
+        match &schema_version {
+            Some(schema_version) if schema_version != EXPECTED_SCHEMA_VERSION => {
+                self.warnings.push(format!(
+                    "Schema version {} differs from {}",
+                    schema_version, EXPECTED_SCHEMA_VERSION
+                ))
+            }
+            Some(_) => {}
+            None => self
+                .warnings
+                .push("AdminVar does not record a schemaVersion".to_string()),
+        }
+        self.schema_version = schema_version;
+    }
+
+    /// Reports each schema upgrade script which did not complete as an incompatibility
+    fn report_incomplete_scripts(&mut self, scripts: Vec<String>) {
+        self.incompatibilities.extend(
+            scripts
+                .into_iter()
+                .map(|script| format!("Schema upgrade script {} did not complete", script)),
+        );
+    }
+
+    /// Compares the columns of each table the models were generated against with those of the
+    /// live database. Missing tables and columns, and unknown columns which must be supplied on
+    /// insert, are incompatibilities; other unknown columns are only warned of.
+    fn compare_tables(
+        &mut self,
+        compiled_tables: &[(&str, &[&str])],
+        live_tables: &HashMap<String, Vec<LiveColumn>>,
+    ) {
+        for (table, compiled_columns) in compiled_tables.iter() {
+            let Some(live_columns) = live_tables.get(*table) else {
+                self.incompatibilities
+                    .push(format!("Table {} is missing", table));
+                continue;
+            };
+            let live_names = live_columns
+                .iter()
+                .map(|column| column.name.as_str())
+                .collect::<HashSet<_>>();
+            for column in compiled_columns.iter() {
+                if !live_names.contains(column) {
+                    self.incompatibilities
+                        .push(format!("Column {}.{} is missing", table, column));
+                }
+            }
+            for column in live_columns
+                .iter()
+                .filter(|column| !compiled_columns.contains(&column.name.as_str()))
+            {
+                if column.required {
+                    self.incompatibilities.push(format!(
+                        "Column {}.{} is required but unknown to the exporter, so inserts will fail",
+                        table, column.name
+                    ));
+                } else {
+                    self.warnings.push(format!(
+                        "Column {}.{} is unknown to the exporter",
+                        table, column.name
+                    ));
+                }
+            }
+        }
+    }
+}
+
+impl Display for CompatibilityReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "ISPyB schema version {} (models generated against {})",
+            self.schema_version.as_deref().unwrap_or("unknown"),
+            EXPECTED_SCHEMA_VERSION
+        )?;
+        for incompatibility in self.incompatibilities.iter() {
+            writeln!(f, "  incompatible: {}", incompatibility)?;
+        }
+        for warning in self.warnings.iter() {
+            writeln!(f, "  warning: {}", warning)?;
+        }
+        Ok(())
+    }
+}
+
+/// A column of the live database, as described by `information_schema`
+struct LiveColumn {
+    name: String,
+    /// Whether an insert which omits the column will fail
+    required: bool,
+}
+
+/// Reads the schema version recorded by ispyb-database in `AdminVar`
+pub async fn schema_version(database: &DatabaseConnection) -> Result<Option<String>, DbErr> {
+    let backend = database.get_database_backend();
+    database
+        .query_one(Statement::from_string(
+            backend,
+            "SELECT value FROM AdminVar WHERE name = 'schemaVersion'".to_string(),
+        ))
+        .await?
+        .map(|row| row.try_get::<String>("", "value"))
+        .transpose()
+}
+
+async fn query_incomplete_scripts(database: &DatabaseConnection) -> Result<Vec<String>, DbErr> {
+    let backend = database.get_database_backend();
+    database
+        .query_all(Statement::from_string(
+            backend,
+            "SELECT scriptName FROM SchemaStatus WHERE schemaStatus IS NULL OR schemaStatus != 'DONE'"
+                .to_string(),
+        ))
+        .await?
+        .into_iter()
+        .map(|row| row.try_get::<String>("", "scriptName"))
+        .collect()
+}
+
+async fn query_live_columns(
+    database: &DatabaseConnection,
+) -> Result<HashMap<String, Vec<LiveColumn>>, DbErr> {
+    let backend = database.get_database_backend();
+    let mut tables = HashMap::<String, Vec<LiveColumn>>::new();
+    for row in database
+        .query_all(Statement::from_string(
+            backend,
+            "SELECT TABLE_NAME, COLUMN_NAME, IS_NULLABLE, COLUMN_DEFAULT, EXTRA FROM information_schema.COLUMNS WHERE TABLE_SCHEMA = DATABASE()"
+                .to_string(),
+        ))
+        .await?
+    {
+        let nullable = row.try_get::<String>("", "IS_NULLABLE")? == "YES";
+        let default = row.try_get::<Option<String>>("", "COLUMN_DEFAULT")?;
+        let auto_increment = row
+            .try_get::<String>("", "EXTRA")?
+            .contains("auto_increment");
+        tables
+            .entry(row.try_get("", "TABLE_NAME")?)
+            .or_default()
+            .push(LiveColumn {
+                name: row.try_get("", "COLUMN_NAME")?,
+                required: !nullable && default.is_none() && !auto_increment,
+            });
+    }
+    Ok(tables)
+}
+
+/// Compares the tables and columns of the connected ISPyB with those the models were generated
+/// against, alongside the recorded schema version and the status of its upgrade scripts
+pub async fn check_compatibility(
+    database: &DatabaseConnection,
+) -> Result<CompatibilityReport, DbErr> {
+    let mut report = CompatibilityReport::default();
+    match schema_version(database).await {
+        Ok(schema_version) => report.compare_schema_version(schema_version),
+        Err(err) => report
+            .warnings
+            .push(format!("Could not read the schema version: {}", err)),
+    }
+    match query_incomplete_scripts(database).await {
+        Ok(scripts) => report.report_incomplete_scripts(scripts),
+        Err(err) => report
+            .warnings
+            .push(format!("Could not read SchemaStatus: {}", err)),
+    }
+    report.compare_tables(COMPILED_TABLES, &query_live_columns(database).await?);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        CompatibilityReport, LiveColumn, SchemaCheck, SchemaCheckOutcome, EXPECTED_SCHEMA_VERSION,
+    };
+    use std::collections::HashMap;
+
+    const COMPILED_TABLES: &[(&str, &[&str])] = &[
+        ("Dewar", &["dewarId", "code"]),
+        ("Shipping", &["shippingId", "shippingName"]),
+    ];
+
+    fn live_tables(tables: &[(&str, &[(&str, bool)])]) -> HashMap<String, Vec<LiveColumn>> {
+        tables
+            .iter()
+            .map(|(table, columns)| {
+                (
+                    table.to_string(),
+                    columns
+                        .iter()
+                        .map(|(name, required)| LiveColumn {
+                            name: name.to_string(),
+                            required: *required,
+                        })
+                        .collect(),
+                )
+            })
+            .collect()
+    }
+
+    fn compare(tables: &[(&str, &[(&str, bool)])]) -> CompatibilityReport {
+        let mut report = CompatibilityReport::default();
+        report.compare_tables(COMPILED_TABLES, &live_tables(tables));
+        report
+    }
+
+    #[test]
+    fn accepts_matching_tables() {
+        let report = compare(&[
+            ("Dewar", &[("dewarId", false), ("code", false)]),
+            ("Shipping", &[("shippingId", false), ("shippingName", true)]),
+            ("BLSample", &[("blSampleId", true)]),
+        ]);
+        assert!(report.is_empty(), "{}", report);
+    }
+
+    #[test]
+    fn reports_missing_tables() {
+        let report = compare(&[("Dewar", &[("dewarId", false), ("code", false)])]);
+        assert_eq!(report.incompatibilities, vec!["Table Shipping is missing"]);
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn reports_missing_columns() {
+        let report = compare(&[
+            ("Dewar", &[("dewarId", false)]),
+            (
+                "Shipping",
+                &[("shippingId", false), ("shippingName", false)],
+            ),
+        ]);
+        assert_eq!(
+            report.incompatibilities,
+            vec!["Column Dewar.code is missing"]
+        );
+        assert!(report.warnings.is_empty());
+    }
+
+    #[test]
+    fn distinguishes_unknown_required_columns_from_unknown_optional_columns() {
+        let report = compare(&[
+            (
+                "Dewar",
+                &[
+                    ("dewarId", false),
+                    ("code", false),
+                    ("barcode", true),
+                    ("comments", false),
+                ],
+            ),
+            (
+                "Shipping",
+                &[("shippingId", false), ("shippingName", false)],
+            ),
+        ]);
+        assert_eq!(
+            report.incompatibilities,
+            vec!["Column Dewar.barcode is required but unknown to the exporter, so inserts will fail"]
+        );
+        assert_eq!(
+            report.warnings,
+            vec!["Column Dewar.comments is unknown to the exporter"]
+        );
+    }
+
+    #[test]
+    fn reports_incomplete_upgrade_scripts() {
+        let mut report = CompatibilityReport::default();
+        report.report_incomplete_scripts(vec!["2023_06_01_AdminVar_bump_version.sql".to_string()]);
+        assert_eq!(
+            report.incompatibilities,
+            vec!["Schema upgrade script 2023_06_01_AdminVar_bump_version.sql did not complete"]
+        );
+    }
+
+    #[test]
+    fn warns_of_other_schema_versions() {
+        let mut report = CompatibilityReport::default();
+        report.compare_schema_version(Some(EXPECTED_SCHEMA_VERSION.to_string()));
+        assert!(report.is_empty(), "{}", report);
+
+        let mut report = CompatibilityReport::default();
+        report.compare_schema_version(Some("1.35.0".to_string()));
+        assert!(report.is_compatible());
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.schema_version.as_deref(), Some("1.35.0"));
+
+        let mut report = CompatibilityReport::default();
+        report.compare_schema_version(None);
+        assert!(report.is_compatible());
+        assert_eq!(
+            report.warnings,
+            vec!["AdminVar does not record a schemaVersion"]
+        );
+    }
+
+    #[test]
+    fn maps_each_schema_check_to_an_outcome() {
+        let matching = CompatibilityReport::default();
+        let differing = CompatibilityReport {
+            warnings: vec!["Column Dewar.comments is unknown to the exporter".to_string()],
+            ..Default::default()
+        };
+        let incompatible = CompatibilityReport {
+            incompatibilities: vec!["Table Shipping is missing".to_string()],
+            ..Default::default()
+        };
+        for (schema_check, report, outcome) in [
+            (SchemaCheck::Strict, &matching, SchemaCheckOutcome::Start),
+            (
+                SchemaCheck::Strict,
+                &differing,
+                SchemaCheckOutcome::StartWithWarnings,
+            ),
+            (
+                SchemaCheck::Strict,
+                &incompatible,
+                SchemaCheckOutcome::Refuse,
+            ),
+            (SchemaCheck::Warn, &matching, SchemaCheckOutcome::Start),
+            (
+                SchemaCheck::Warn,
+                &differing,
+                SchemaCheckOutcome::StartWithWarnings,
+            ),
+            (
+                SchemaCheck::Warn,
+                &incompatible,
+                SchemaCheckOutcome::StartWithWarnings,
+            ),
+            (SchemaCheck::Off, &matching, SchemaCheckOutcome::Start),
+            (SchemaCheck::Off, &differing, SchemaCheckOutcome::Start),
+            (SchemaCheck::Off, &incompatible, SchemaCheckOutcome::Start),
+        ] {
+            assert_eq!(
+                schema_check.outcome(report),
+                outcome,
+                "{:?} of {}",
+                schema_check,
+                report
+            );
+        }
+    }
+}
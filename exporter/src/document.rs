@@ -0,0 +1,302 @@
+use crate::{
+    api::{DewarInput, PinInput, PuckInput},
+    config::ShipmentDefaults,
+    export::{DewarManifest, PinManifest, PuckManifest, ShipmentManifest},
+};
+use derive_more::{Display, Error, From};
+use models::proposal;
+use schemars::{schema::RootSchema, schema_for, JsonSchema};
+use sea_orm::{ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter};
+use serde::{Deserialize, Serialize};
+
+/// The version of the shipment document format written by this exporter. It must be incremented
+/// whenever a field is added, removed or changes meaning.
+pub const SHIPMENT_DOCUMENT_VERSION: u32 = 1;
+
+/// The JSON Schema of the current version of the shipment document format, as written by
+/// `document-schema`
+pub const SHIPMENT_DOCUMENT_SCHEMA: &str = include_str!("../shipment-document.schema.json");
+
+#[derive(Debug, Display, Error, From)]
+pub enum DocumentError {
+    #[display(fmt = "Could not parse shipment document: {}", _0)]
+    Parse(serde_json::Error),
+    #[display(
+        fmt = "Shipment document version {} is not supported, expected {}",
+        _0,
+        SHIPMENT_DOCUMENT_VERSION
+    )]
+    #[from(ignore)]
+    UnsupportedVersion(#[error(not(source))] u32),
+}
+
+/// The proposal under which a shipment was made, identified by code and number such that it can
+/// be found in any ISPyB instance
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ProposalReference {
+    pub code: String,
+    pub number: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PinDocument {
+    pub code: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub protein_acronym: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub smiles: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comments: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct PuckDocument {
+    pub code: String,
+    pub pins: Vec<PinDocument>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct DewarDocument {
+    pub code: String,
+    pub pucks: Vec<PuckDocument>,
+}
+
+/// A portable description of a shipment, from which it can be recreated in another ISPyB instance.
+/// Database ids are not included, as they differ between instances.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase", deny_unknown_fields)]
+pub struct ShipmentDocument {
+    #[schemars(range(min = 1, max = 1))]
+    pub version: u32,
+    pub proposal: ProposalReference,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub comments: Option<String>,
+    pub dewars: Vec<DewarDocument>,
+}
+
+impl From<PinManifest> for PinDocument {
+    fn from(value: PinManifest) -> Self {
+        Self {
+            code: value.code.unwrap_or_default(),
+            location: value.location,
+            name: value.name,
+            protein_acronym: value.protein_acronym,
+            smiles: value.smiles,
+            comments: value.comments,
+        }
+    }
+}
+
+impl From<PuckManifest> for PuckDocument {
+    fn from(value: PuckManifest) -> Self {
+        Self {
+            code: value.code.unwrap_or_default(),
+            pins: value.pins.into_iter().map(PinDocument::from).collect(),
+        }
+    }
+}
+
+impl From<DewarManifest> for DewarDocument {
+    fn from(value: DewarManifest) -> Self {
+        Self {
+            code: value.code.unwrap_or_default(),
+            pucks: value.pucks.into_iter().map(PuckDocument::from).collect(),
+        }
+    }
+}
+
+impl From<PinDocument> for PinInput {
+    fn from(value: PinDocument) -> Self {
+        Self {
+            code: value.code,
+            location: value.location,
+            name: value.name,
+            protein_acronym: value.protein_acronym,
+            smiles: value.smiles,
+            comments: value.comments,
+        }
+    }
+}
+
+impl From<PuckDocument> for PuckInput {
+    fn from(value: PuckDocument) -> Self {
+        Self {
+            code: value.code,
+            pins: value.pins.into_iter().map(PinInput::from).collect(),
+        }
+    }
+}
+
+impl From<DewarDocument> for DewarInput {
+    fn from(value: DewarDocument) -> Self {
+        Self {
+            code: value.code,
+            pucks: value.pucks.into_iter().map(PuckInput::from).collect(),
+        }
+    }
+}
+
+impl ShipmentDocument {
+    /// Describes a shipment, as loaded from ISPyB
+    pub async fn from_manifest(
+        manifest: ShipmentManifest,
+        database: &DatabaseConnection,
+    ) -> Result<Self, DbErr> {
+        let proposal = proposal::Entity::find_by_id(manifest.proposal_id)
+            .one(database)
+            .await?
+            .ok_or_else(|| {
+                DbErr::RecordNotFound(format!("No proposal with id {}", manifest.proposal_id))
+            })?;
+        Ok(Self {
+            version: SHIPMENT_DOCUMENT_VERSION,
+            proposal: ProposalReference {
+                code: proposal.proposal_code.unwrap_or_default(),
+                number: proposal.proposal_number.unwrap_or_default(),
+            },
+            name: manifest.name,
+            comments: manifest.comments,
+            dewars: manifest
+                .dewars
+                .into_iter()
+                .map(DewarDocument::from)
+                .collect(),
+        })
+    }
+
+    /// Describes the current version of the format as a JSON Schema
+    pub fn json_schema() -> RootSchema {
+        schema_for!(Self)
+    }
+
+    /// Parses a document, rejecting those written in any other version of the format
+    pub fn parse(content: &str) -> Result<Self, DocumentError> {
+        let version = serde_json::from_str::<VersionOnly>(content)?.version;
+        if version != SHIPMENT_DOCUMENT_VERSION {
+            return Err(DocumentError::UnsupportedVersion(version));
+        }
+        Ok(serde_json::from_str(content)?)
+    }
+
+    /// Finds the id of the referenced proposal in the connected ISPyB instance
+    pub async fn find_proposal_id(
+        &self,
+        database: &DatabaseConnection,
+    ) -> Result<Option<u32>, DbErr> {
+        Ok(proposal::Entity::find()
+            .filter(proposal::Column::ProposalCode.eq(self.proposal.code.as_str()))
+            .filter(proposal::Column::ProposalNumber.eq(self.proposal.number.as_str()))
+            .one(database)
+            .await?
+            .map(|proposal| proposal.proposal_id))
+    }
+
+    /// The name and comments with which to recreate the shipment, each falling back to the
+    /// configured default if the document has none
+    pub fn shipment_defaults(&self, defaults: &ShipmentDefaults) -> ShipmentDefaults {
+        ShipmentDefaults {
+            name: self.name.clone().unwrap_or_else(|| defaults.name.clone()),
+            comments: self.comments.clone().or_else(|| defaults.comments.clone()),
+        }
+    }
+
+    pub fn into_dewars(self) -> Vec<DewarInput> {
+        self.dewars.into_iter().map(DewarInput::from).collect()
+    }
+}
+
+/// The version field alone, read before the remainder of the document such that documents in a
+/// newer format are reported as such rather than as malformed
+#[derive(Debug, Deserialize)]
+struct VersionOnly {
+    version: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        DewarDocument, DocumentError, PinDocument, ProposalReference, PuckDocument,
+        ShipmentDocument, SHIPMENT_DOCUMENT_SCHEMA, SHIPMENT_DOCUMENT_VERSION,
+    };
+
+    fn document() -> ShipmentDocument {
+        ShipmentDocument {
+            version: SHIPMENT_DOCUMENT_VERSION,
+            proposal: ProposalReference {
+                code: "mx".to_string(),
+                number: "12345".to_string(),
+            },
+            name: Some("Fragment screen".to_string()),
+            comments: Some("Handle with care".to_string()),
+            dewars: vec![DewarDocument {
+                code: "DLS-1".to_string(),
+                pucks: vec![PuckDocument {
+                    code: "CPS-1".to_string(),
+                    pins: vec![
+                        PinDocument {
+                            code: "P-1".to_string(),
+                            location: Some("1".to_string()),
+                            name: Some("x0001".to_string()),
+                            protein_acronym: Some("LYS".to_string()),
+                            smiles: Some("CCO".to_string()),
+                            comments: Some("Compound Z1234".to_string()),
+                        },
+                        PinDocument {
+                            code: "P-2".to_string(),
+                            location: None,
+                            name: None,
+                            protein_acronym: None,
+                            smiles: None,
+                            comments: None,
+                        },
+                    ],
+                }],
+            }],
+        }
+    }
+
+    #[test]
+    fn parses_written_documents() {
+        let content = serde_json::to_string_pretty(&document()).unwrap();
+        assert_eq!(ShipmentDocument::parse(&content).unwrap(), document());
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut content = serde_json::to_value(document()).unwrap();
+        content["version"] = 2.into();
+        assert!(matches!(
+            ShipmentDocument::parse(&content.to_string()),
+            Err(DocumentError::UnsupportedVersion(2))
+        ));
+    }
+
+    #[test]
+    fn rejects_unknown_fields() {
+        let mut content = serde_json::to_value(document()).unwrap();
+        content["dewars"][0]["pucks"][0]["pins"][0]["colour"] = "red".into();
+        assert!(matches!(
+            ShipmentDocument::parse(&content.to_string()),
+            Err(DocumentError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn publishes_the_current_schema() {
+        assert_eq!(
+            SHIPMENT_DOCUMENT_SCHEMA,
+            serde_json::to_string_pretty(&ShipmentDocument::json_schema()).unwrap() + "\n",
+            "shipment-document.schema.json is outdated, regenerate it with document-schema"
+        );
+    }
+}
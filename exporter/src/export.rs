@@ -0,0 +1,529 @@
+use crate::{
+    api::ProposalAccess,
+    labels::{render_labels, LabelError},
+};
+use clap::ValueEnum;
+use derive_more::{Display, Error, From};
+use models::{
+    bl_sample, container, crystal, dewar, lab_contact, person, proposal, protein, shipping,
+};
+use printpdf::{
+    BuiltinFont, IndirectFontRef, Mm, PdfDocument, PdfDocumentReference, PdfLayerReference,
+};
+use sea_orm::{ColumnTrait, DatabaseConnection, DbErr, EntityTrait, QueryFilter, QueryOrder};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+    Pdf,
+}
+
+impl ExportFormat {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Csv => "text/csv",
+            Self::Json => "application/json",
+            Self::Pdf => "application/pdf",
+        }
+    }
+}
+
+#[derive(Debug, Display, Error, From)]
+pub enum ExportError {
+    #[display(fmt = "Could not write CSV: {}", _0)]
+    Csv(csv::Error),
+    #[display(fmt = "Could not write JSON: {}", _0)]
+    Json(serde_json::Error),
+    #[display(fmt = "Could not write PDF: {}", _0)]
+    Pdf(printpdf::Error),
+    #[display(fmt = "Could not render labels: {}", _0)]
+    Labels(LabelError),
+}
+
+/// A document describing a shipment, as served at `/shipments/{id}/{file name}`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShipmentFile {
+    Manifest(ExportFormat),
+    Labels,
+}
+
+impl ShipmentFile {
+    pub fn from_file_name(file_name: &str) -> Option<Self> {
+        match file_name {
+            "manifest.csv" => Some(Self::Manifest(ExportFormat::Csv)),
+            "manifest.json" => Some(Self::Manifest(ExportFormat::Json)),
+            "manifest.pdf" => Some(Self::Manifest(ExportFormat::Pdf)),
+            "labels.pdf" => Some(Self::Labels),
+            _ => None,
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Manifest(format) => format.content_type(),
+            Self::Labels => ExportFormat::Pdf.content_type(),
+        }
+    }
+}
+
+/// The person to contact about a shipment
+#[derive(Debug, Clone, Serialize)]
+pub struct LabContactManifest {
+    pub name: String,
+    pub email_address: Option<String>,
+    pub phone_number: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PinManifest {
+    pub id: u32,
+    pub code: Option<String>,
+    pub location: Option<String>,
+    pub name: Option<String>,
+    pub protein_acronym: Option<String>,
+    pub smiles: Option<String>,
+    pub comments: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PuckManifest {
+    pub id: u32,
+    pub code: Option<String>,
+    pub pins: Vec<PinManifest>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DewarManifest {
+    pub id: u32,
+    pub code: Option<String>,
+    /// The barcode assigned to the dewar by the facility
+    pub facility_code: Option<String>,
+    pub pucks: Vec<PuckManifest>,
+}
+
+/// The contents of a shipment, from its dewars down to the individual pins
+#[derive(Debug, Clone, Serialize)]
+pub struct ShipmentManifest {
+    pub id: u32,
+    pub name: Option<String>,
+    pub comments: Option<String>,
+    pub proposal_id: u32,
+    /// The proposal code and number, for example `mx12345`
+    pub proposal: String,
+    pub lab_contact: Option<LabContactManifest>,
+    pub dewars: Vec<DewarManifest>,
+}
+
+fn person_name(person: &person::Model) -> String {
+    [&person.title, &person.given_name, &person.family_name]
+        .into_iter()
+        .flatten()
+        .cloned()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+async fn load_lab_contact(
+    lab_contact_id: u32,
+    database: &DatabaseConnection,
+) -> Result<Option<LabContactManifest>, DbErr> {
+    let Some(lab_contact) = lab_contact::Entity::find_by_id(lab_contact_id)
+        .one(database)
+        .await?
+    else {
+        return Ok(None);
+    };
+    Ok(person::Entity::find_by_id(lab_contact.person_id)
+        .one(database)
+        .await?
+        .map(|person| LabContactManifest {
+            name: person_name(&person),
+            email_address: person.email_address,
+            phone_number: person.phone_number,
+        }))
+}
+
+async fn load_protein_acronym(
+    crystal_id: u32,
+    database: &DatabaseConnection,
+) -> Result<Option<String>, DbErr> {
+    let Some(crystal) = crystal::Entity::find_by_id(crystal_id)
+        .one(database)
+        .await?
+    else {
+        return Ok(None);
+    };
+    Ok(protein::Entity::find_by_id(crystal.protein_id)
+        .one(database)
+        .await?
+        .and_then(|protein| protein.acronym))
+}
+
+impl ShipmentManifest {
+    /// Loads a shipment and everything within it, provided it belongs to an accessible proposal
+    pub async fn load(
+        shipment_id: u32,
+        access: &ProposalAccess,
+        database: &DatabaseConnection,
+    ) -> Result<Option<Self>, DbErr> {
+        let Some(shipping) = shipping::Entity::find_by_id(shipment_id)
+            .filter(access.shipments())
+            .one(database)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let proposal = proposal::Entity::find_by_id(shipping.proposal_id)
+            .one(database)
+            .await?;
+        let lab_contact = match shipping.sending_lab_contact_id {
+            Some(lab_contact_id) => load_lab_contact(lab_contact_id, database).await?,
+            None => None,
+        };
+
+        let mut dewars = Vec::new();
+        for dewar in dewar::Entity::find()
+            .filter(dewar::Column::ShippingId.eq(shipping.shipping_id))
+            .order_by_asc(dewar::Column::DewarId)
+            .all(database)
+            .await?
+        {
+            let mut pucks = Vec::new();
+            for puck in container::Entity::find()
+                .filter(container::Column::DewarId.eq(dewar.dewar_id))
+                .order_by_asc(container::Column::ContainerId)
+                .all(database)
+                .await?
+            {
+                let mut pins = Vec::new();
+                for pin in bl_sample::Entity::find()
+                    .filter(bl_sample::Column::ContainerId.eq(puck.container_id))
+                    .order_by_asc(bl_sample::Column::BlSampleId)
+                    .all(database)
+                    .await?
+                {
+                    let protein_acronym = match pin.crystal_id {
+                        Some(crystal_id) => load_protein_acronym(crystal_id, database).await?,
+                        None => None,
+                    };
+                    pins.push(PinManifest {
+                        id: pin.bl_sample_id,
+                        code: pin.code,
+                        location: pin.location,
+                        name: pin.name,
+                        protein_acronym,
+                        smiles: pin.smiles,
+                        comments: pin.comments,
+                    });
+                }
+                pucks.push(PuckManifest {
+                    id: puck.container_id,
+                    code: puck.code,
+                    pins,
+                });
+            }
+            dewars.push(DewarManifest {
+                id: dewar.dewar_id,
+                code: dewar.code,
+                facility_code: dewar.facility_code,
+                pucks,
+            });
+        }
+
+        Ok(Some(Self {
+            id: shipping.shipping_id,
+            name: shipping.shipping_name,
+            comments: shipping.comments,
+            proposal_id: shipping.proposal_id,
+            proposal: proposal
+                .map(|proposal| {
+                    format!(
+                        "{}{}",
+                        proposal.proposal_code.unwrap_or_default(),
+                        proposal.proposal_number.unwrap_or_default()
+                    )
+                })
+                .unwrap_or_default(),
+            lab_contact,
+            dewars,
+        }))
+    }
+
+    pub fn render(&self, format: ExportFormat) -> Result<Vec<u8>, ExportError> {
+        match format {
+            ExportFormat::Csv => self.to_csv(),
+            ExportFormat::Json => Ok(serde_json::to_vec_pretty(self)?),
+            ExportFormat::Pdf => self.to_pdf(),
+        }
+    }
+
+    pub fn render_file(&self, file: ShipmentFile) -> Result<Vec<u8>, ExportError> {
+        match file {
+            ShipmentFile::Manifest(format) => self.render(format),
+            ShipmentFile::Labels => Ok(render_labels(self)?),
+        }
+    }
+
+    /// Writes a row for each pin, with the same columns as are accepted by the manifest import
+    fn to_csv(&self) -> Result<Vec<u8>, ExportError> {
+        let mut writer = csv::Writer::from_writer(Vec::new());
+        writer.write_record([
+            "dewar barcode",
+            "puck barcode",
+            "position",
+            "pin barcode",
+            "protein acronym",
+            "sample name",
+            "smiles",
+        ])?;
+        for dewar in self.dewars.iter() {
+            for puck in dewar.pucks.iter() {
+                for pin in puck.pins.iter() {
+                    writer.write_record([
+                        dewar.code.as_deref().unwrap_or_default(),
+                        puck.code.as_deref().unwrap_or_default(),
+                        pin.location.as_deref().unwrap_or_default(),
+                        pin.code.as_deref().unwrap_or_default(),
+                        pin.protein_acronym.as_deref().unwrap_or_default(),
+                        pin.name.as_deref().unwrap_or_default(),
+                        pin.smiles.as_deref().unwrap_or_default(),
+                    ])?;
+                }
+            }
+        }
+        writer
+            .into_inner()
+            .map_err(|err| ExportError::Csv(err.into_error().into()))
+    }
+
+    /// Renders a printable packing list, listing the contents of each dewar and puck
+    fn to_pdf(&self) -> Result<Vec<u8>, ExportError> {
+        let title = format!("Packing list for shipment {}", self.id);
+        let mut writer = PdfTextWriter::new(&title)?;
+        writer.line(&title, 16.0);
+        writer.line(&format!("Proposal: {}", self.proposal), 11.0);
+        if let Some(name) = &self.name {
+            writer.line(&format!("Name: {}", name), 11.0);
+        }
+        if let Some(lab_contact) = &self.lab_contact {
+            let details = [&lab_contact.email_address, &lab_contact.phone_number]
+                .into_iter()
+                .flatten()
+                .cloned()
+                .collect::<Vec<_>>();
+            writer.line(
+                &format!("Lab contact: {} {}", lab_contact.name, details.join(", ")),
+                11.0,
+            );
+        }
+        for dewar in self.dewars.iter() {
+            writer.gap();
+            writer.line(
+                &format!(
+                    "Dewar {} ({} pucks)",
+                    dewar.code.as_deref().unwrap_or("unlabelled"),
+                    dewar.pucks.len()
+                ),
+                13.0,
+            );
+            for puck in dewar.pucks.iter() {
+                writer.line(
+                    &format!(
+                        "    Puck {} ({} pins)",
+                        puck.code.as_deref().unwrap_or("unlabelled"),
+                        puck.pins.len()
+                    ),
+                    11.0,
+                );
+                for pin in puck.pins.iter() {
+                    writer.line(
+                        &format!(
+                            "        {:>3}  {}  {}  {}",
+                            pin.location.as_deref().unwrap_or("-"),
+                            pin.code.as_deref().unwrap_or("unlabelled"),
+                            pin.name.as_deref().unwrap_or_default(),
+                            pin.protein_acronym.as_deref().unwrap_or_default()
+                        ),
+                        9.0,
+                    );
+                }
+            }
+        }
+        Ok(writer.finish()?)
+    }
+}
+
+const PAGE_WIDTH: Mm = Mm(210.0);
+const PAGE_HEIGHT: Mm = Mm(297.0);
+const MARGIN: f64 = 15.0;
+
+/// Lays out lines of text down A4 pages, starting a new page when one is full
+struct PdfTextWriter {
+    document: PdfDocumentReference,
+    font: IndirectFontRef,
+    layer: PdfLayerReference,
+    y: f64,
+}
+
+impl PdfTextWriter {
+    fn new(title: &str) -> Result<Self, printpdf::Error> {
+        let (document, page, layer) = PdfDocument::new(title, PAGE_WIDTH, PAGE_HEIGHT, "Layer 1");
+        let font = document.add_builtin_font(BuiltinFont::Courier)?;
+        let layer = document.get_page(page).get_layer(layer);
+        Ok(Self {
+            document,
+            font,
+            layer,
+            y: PAGE_HEIGHT.0 - MARGIN,
+        })
+    }
+
+    fn line(&mut self, text: &str, font_size: f64) {
+        let height = font_size * 0.5;
+        if self.y - height < MARGIN {
+            let (page, layer) = self.document.add_page(PAGE_WIDTH, PAGE_HEIGHT, "Layer 1");
+            self.layer = self.document.get_page(page).get_layer(layer);
+            self.y = PAGE_HEIGHT.0 - MARGIN;
+        }
+        self.y -= height;
+        self.layer
+            .use_text(text, font_size, Mm(MARGIN), Mm(self.y), &self.font);
+    }
+
+    fn gap(&mut self) {
+        self.y -= 4.0;
+    }
+
+    fn finish(self) -> Result<Vec<u8>, printpdf::Error> {
+        self.document.save_to_bytes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{DewarManifest, ExportFormat, PinManifest, PuckManifest, ShipmentManifest};
+    use crate::{
+        api::{DewarInput, PinInput, PuckInput},
+        manifest::{Manifest, ManifestFormat},
+    };
+
+    fn pin(
+        id: u32,
+        code: &str,
+        location: &str,
+        name: Option<&str>,
+        smiles: Option<&str>,
+    ) -> PinManifest {
+        PinManifest {
+            id,
+            code: Some(code.to_string()),
+            location: Some(location.to_string()),
+            name: name.map(str::to_string),
+            protein_acronym: Some("LYS".to_string()),
+            smiles: smiles.map(str::to_string),
+            comments: None,
+        }
+    }
+
+    fn pin_input(code: &str, location: &str, name: Option<&str>, smiles: Option<&str>) -> PinInput {
+        PinInput {
+            code: code.to_string(),
+            location: Some(location.to_string()),
+            name: name.map(str::to_string),
+            protein_acronym: Some("LYS".to_string()),
+            smiles: smiles.map(str::to_string),
+            comments: None,
+        }
+    }
+
+    #[test]
+    fn writes_csv_which_imports_as_the_same_shipment() {
+        let shipment = ShipmentManifest {
+            id: 1,
+            name: Some("Shipment 1".to_string()),
+            comments: None,
+            proposal_id: 2,
+            proposal: "mx12345".to_string(),
+            lab_contact: None,
+            dewars: vec![
+                DewarManifest {
+                    id: 3,
+                    code: Some("DLS-1".to_string()),
+                    facility_code: Some("DLS-MX-0001".to_string()),
+                    pucks: vec![
+                        PuckManifest {
+                            id: 4,
+                            code: Some("CPS-1".to_string()),
+                            pins: vec![
+                                pin(
+                                    5,
+                                    "P-1",
+                                    "1",
+                                    Some("Crystal 1, soaked"),
+                                    Some("CC(=O)Oc1ccccc1C(=O)O"),
+                                ),
+                                pin(6, "P-2", "2", None, None),
+                            ],
+                        },
+                        PuckManifest {
+                            id: 7,
+                            code: Some("CPS-2".to_string()),
+                            pins: vec![pin(8, "P-3", "1", Some("Crystal 3"), Some("CCO"))],
+                        },
+                    ],
+                },
+                DewarManifest {
+                    id: 9,
+                    code: Some("DLS-2".to_string()),
+                    facility_code: None,
+                    pucks: vec![PuckManifest {
+                        id: 10,
+                        code: Some("CPS-3".to_string()),
+                        pins: vec![pin(11, "P-4", "16", Some("Crystal 4"), Some("c1ccncc1"))],
+                    }],
+                },
+            ],
+        };
+
+        let csv = shipment.render(ExportFormat::Csv).unwrap();
+        let dewars = Manifest::parse(&csv, ManifestFormat::Csv)
+            .unwrap()
+            .into_dewars();
+
+        assert_eq!(
+            dewars,
+            vec![
+                DewarInput {
+                    code: "DLS-1".to_string(),
+                    pucks: vec![
+                        PuckInput {
+                            code: "CPS-1".to_string(),
+                            pins: vec![
+                                pin_input(
+                                    "P-1",
+                                    "1",
+                                    Some("Crystal 1, soaked"),
+                                    Some("CC(=O)Oc1ccccc1C(=O)O")
+                                ),
+                                pin_input("P-2", "2", None, None),
+                            ],
+                        },
+                        PuckInput {
+                            code: "CPS-2".to_string(),
+                            pins: vec![pin_input("P-3", "1", Some("Crystal 3"), Some("CCO"))],
+                        },
+                    ],
+                },
+                DewarInput {
+                    code: "DLS-2".to_string(),
+                    pucks: vec![PuckInput {
+                        code: "CPS-3".to_string(),
+                        pins: vec![pin_input("P-4", "16", Some("Crystal 4"), Some("c1ccncc1"))],
+                    }],
+                },
+            ]
+        );
+    }
+}
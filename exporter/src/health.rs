@@ -0,0 +1,79 @@
+use crate::{api::ShipmentEvent, broker::SharedEventBroker, compatibility};
+use axum::{http::StatusCode, response::IntoResponse, Extension, Json};
+use sea_orm::{ConnectionTrait, DatabaseConnection, Statement};
+use serde::Serialize;
+use std::time::Duration;
+
+/// The commit from which the exporter was built, as recorded by the build script
+const GIT_SHA: &str = env!("EXPORTER_GIT_SHA");
+
+/// The longest a probe waits for the exporter to respond before considering it unhealthy
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reports that the process is alive and serving requests
+pub async fn healthz() -> impl IntoResponse {
+    (StatusCode::OK, "ok")
+}
+
+/// Reports whether the exporter can serve requests, by querying ISPyB and checking the broker
+pub async fn readyz(
+    Extension(database): Extension<DatabaseConnection>,
+    Extension(event_broker): Extension<SharedEventBroker<ShipmentEvent>>,
+) -> impl IntoResponse {
+    let mut problems = Vec::new();
+    if let Err(err) = database
+        .execute(Statement::from_string(
+            database.get_database_backend(),
+            "SELECT 1".to_string(),
+        ))
+        .await
+    {
+        problems.push(format!("ISPyB is unreachable: {}", err));
+    }
+    if !event_broker.is_ready() {
+        problems.push("The event broker is not connected".to_string());
+    }
+    if problems.is_empty() {
+        (StatusCode::OK, "ready".to_string())
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, problems.join("\n"))
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VersionInfo {
+    version: &'static str,
+    git_sha: &'static str,
+    /// The version recorded by the connected ISPyB, if it could be read
+    ispyb_schema_version: Option<String>,
+}
+
+/// Describes the build of the exporter and the version of the ISPyB it is connected to
+pub async fn version(Extension(database): Extension<DatabaseConnection>) -> Json<VersionInfo> {
+    Json(VersionInfo {
+        version: env!("CARGO_PKG_VERSION"),
+        git_sha: GIT_SHA,
+        ispyb_schema_version: compatibility::schema_version(&database)
+            .await
+            .ok()
+            .flatten(),
+    })
+}
+
+/// Requests a health endpoint of a running exporter, succeeding if it responds with success
+pub async fn probe(url: &str) -> Result<(), String> {
+    let response = reqwest::Client::new()
+        .get(url)
+        .timeout(PROBE_TIMEOUT)
+        .send()
+        .await
+        .map_err(|err| format!("Could not reach {}: {}", url, err))?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(())
+    } else {
+        let body = response.text().await.unwrap_or_default();
+        Err(format!("{} responded with {}: {}", url, status, body))
+    }
+}
@@ -0,0 +1,413 @@
+use crate::{
+    api::{NodeId, NodeType},
+    export::{DewarManifest, PuckManifest, ShipmentManifest},
+};
+use async_graphql::ID;
+use barcoders::sym::code128::Code128;
+use derive_more::{Display, Error, From};
+use printpdf::{BuiltinFont, IndirectFontRef, Line, Mm, PdfDocument, PdfLayerReference, Point};
+use qrcode::{Color, QrCode};
+
+/// Dewar labels are sized for 4 by 6 inch shipping label stock
+const DEWAR_LABEL_SIZE: (f64, f64) = (102.0, 152.0);
+
+/// Puck labels are sized for 62 by 29 mm address label rolls
+const PUCK_LABEL_SIZE: (f64, f64) = (62.0, 29.0);
+
+const MARGIN: f64 = 4.0;
+
+#[derive(Debug, Display, Error, From)]
+pub enum LabelError {
+    #[display(fmt = "Could not encode barcode: {}", _0)]
+    Barcode(barcoders::error::Error),
+    #[display(fmt = "Could not encode QR code: {}", _0)]
+    QrCode(qrcode::types::QrError),
+    #[display(fmt = "Could not write PDF: {}", _0)]
+    Pdf(printpdf::Error),
+}
+
+/// Renders a label for each dewar, bearing its facility code and the shipment details, followed by
+/// a label for each puck. Every label is a page of its own, sized for a label printer.
+pub fn render_labels(manifest: &ShipmentManifest) -> Result<Vec<u8>, LabelError> {
+    let (document, page, layer) = PdfDocument::new(
+        format!("Labels for shipment {}", manifest.id),
+        Mm(DEWAR_LABEL_SIZE.0),
+        Mm(DEWAR_LABEL_SIZE.1),
+        "Label",
+    );
+    let fonts = Fonts {
+        regular: document.add_builtin_font(BuiltinFont::Helvetica)?,
+        bold: document.add_builtin_font(BuiltinFont::HelveticaBold)?,
+    };
+    let mut first_page = Some(document.get_page(page).get_layer(layer));
+    let mut next_page = |size: (f64, f64)| {
+        first_page.take().unwrap_or_else(|| {
+            let (page, layer) = document.add_page(Mm(size.0), Mm(size.1), "Label");
+            document.get_page(page).get_layer(layer)
+        })
+    };
+    if manifest.dewars.is_empty() {
+        next_page(DEWAR_LABEL_SIZE);
+    }
+    for dewar in manifest.dewars.iter() {
+        draw_dewar_label(&next_page(DEWAR_LABEL_SIZE), &fonts, manifest, dewar)?;
+    }
+    for dewar in manifest.dewars.iter() {
+        for puck in dewar.pucks.iter() {
+            draw_puck_label(&next_page(PUCK_LABEL_SIZE), &fonts, dewar, puck)?;
+        }
+    }
+    Ok(document.save_to_bytes()?)
+}
+
+struct Fonts {
+    regular: IndirectFontRef,
+    bold: IndirectFontRef,
+}
+
+/// The text encoded in the barcode and the QR code of a label
+#[derive(Debug, PartialEq, Eq)]
+struct Payloads {
+    barcode: String,
+    qr_code: ID,
+}
+
+impl Payloads {
+    /// Dewars are labelled with the facility code, where one has been assigned, in preference to
+    /// their own barcode, and with their node ID where neither is known
+    fn of_dewar(dewar: &DewarManifest) -> Self {
+        let qr_code = ID::from(NodeId::new(NodeType::Dewar, dewar.id));
+        let barcode = dewar
+            .facility_code
+            .clone()
+            .or_else(|| dewar.code.clone())
+            .unwrap_or_else(|| qr_code.0.clone());
+        Self { barcode, qr_code }
+    }
+
+    fn of_puck(puck: &PuckManifest) -> Self {
+        let qr_code = ID::from(NodeId::new(NodeType::Puck, puck.id));
+        let barcode = puck.code.clone().unwrap_or_else(|| qr_code.0.clone());
+        Self { barcode, qr_code }
+    }
+}
+
+fn draw_dewar_label(
+    layer: &PdfLayerReference,
+    fonts: &Fonts,
+    manifest: &ShipmentManifest,
+    dewar: &DewarManifest,
+) -> Result<(), LabelError> {
+    let (width, height) = DEWAR_LABEL_SIZE;
+    let Payloads { barcode, qr_code } = Payloads::of_dewar(dewar);
+    let mut y = height - MARGIN;
+
+    y -= 8.0;
+    layer.use_text("Diamond Light Source", 14.0, Mm(MARGIN), Mm(y), &fonts.bold);
+    y -= 12.0;
+    layer.use_text(&barcode, 24.0, Mm(MARGIN), Mm(y), &fonts.bold);
+    y -= 26.0;
+    draw_code128(layer, &barcode, MARGIN, y, width - 2.0 * MARGIN, 22.0)?;
+
+    let mut details = vec![
+        format!("Proposal: {}", manifest.proposal),
+        format!(
+            "Shipment: {} {}",
+            manifest.id,
+            manifest.name.as_deref().unwrap_or_default()
+        ),
+        format!("Dewar: {}", dewar.code.as_deref().unwrap_or("unlabelled")),
+        format!("Pucks: {}", dewar.pucks.len()),
+    ];
+    if let Some(lab_contact) = &manifest.lab_contact {
+        details.push(format!("Contact: {}", lab_contact.name));
+        details.extend(lab_contact.email_address.clone());
+        details.extend(lab_contact.phone_number.clone());
+    }
+    y -= 4.0;
+    for detail in details {
+        y -= 7.0;
+        layer.use_text(detail, 11.0, Mm(MARGIN), Mm(y), &fonts.regular);
+    }
+
+    let qr_size = 36.0;
+    draw_qr_code(layer, &qr_code, width - MARGIN - qr_size, MARGIN, qr_size)?;
+    Ok(())
+}
+
+fn draw_puck_label(
+    layer: &PdfLayerReference,
+    fonts: &Fonts,
+    dewar: &DewarManifest,
+    puck: &PuckManifest,
+) -> Result<(), LabelError> {
+    let (width, height) = PUCK_LABEL_SIZE;
+    let Payloads { barcode, qr_code } = Payloads::of_puck(puck);
+    let qr_size = height - 2.0 * MARGIN;
+    let text_width = width - 3.0 * MARGIN - qr_size;
+
+    layer.use_text(
+        &barcode,
+        10.0,
+        Mm(MARGIN),
+        Mm(height - MARGIN - 4.0),
+        &fonts.bold,
+    );
+    layer.use_text(
+        format!(
+            "Dewar {} - {} pins",
+            dewar.code.as_deref().unwrap_or("unlabelled"),
+            puck.pins.len()
+        ),
+        6.0,
+        Mm(MARGIN),
+        Mm(height - MARGIN - 8.0),
+        &fonts.regular,
+    );
+    draw_code128(layer, &barcode, MARGIN, MARGIN, text_width, 10.0)?;
+    draw_qr_code(layer, &qr_code, width - MARGIN - qr_size, MARGIN, qr_size)?;
+    Ok(())
+}
+
+/// Fills a rectangle, with its lower left corner at the given position, in millimetres
+fn fill_rectangle(layer: &PdfLayerReference, x: f64, y: f64, width: f64, height: f64) {
+    layer.add_shape(Line {
+        points: vec![
+            (Point::new(Mm(x), Mm(y)), false),
+            (Point::new(Mm(x + width), Mm(y)), false),
+            (Point::new(Mm(x + width), Mm(y + height)), false),
+            (Point::new(Mm(x), Mm(y + height)), false),
+        ],
+        is_closed: true,
+        has_fill: true,
+        has_stroke: false,
+        is_clipping_path: false,
+    });
+}
+
+/// Encodes the data as the bars and spaces of a Code 128 barcode, using character set B
+fn code128_modules(data: &str) -> Result<Vec<u8>, LabelError> {
+    Ok(Code128::new(format!("\u{0181}{}", data))?.encode())
+}
+
+/// Draws a Code 128 barcode scaled to fit within the given width
+fn draw_code128(
+    layer: &PdfLayerReference,
+    data: &str,
+    x: f64,
+    y: f64,
+    max_width: f64,
+    height: f64,
+) -> Result<(), LabelError> {
+    let modules = code128_modules(data)?;
+    let module_width = (max_width / modules.len() as f64).min(0.5);
+    let mut index = 0;
+    while index < modules.len() {
+        let run = modules[index..]
+            .iter()
+            .take_while(|&&module| module == modules[index])
+            .count();
+        if modules[index] == 1 {
+            fill_rectangle(
+                layer,
+                x + index as f64 * module_width,
+                y,
+                run as f64 * module_width,
+                height,
+            );
+        }
+        index += run;
+    }
+    Ok(())
+}
+
+/// Draws a square QR code with its lower left corner at the given position
+fn draw_qr_code(
+    layer: &PdfLayerReference,
+    data: &str,
+    x: f64,
+    y: f64,
+    size: f64,
+) -> Result<(), LabelError> {
+    let code = QrCode::new(data.as_bytes())?;
+    let modules = code.width();
+    let module_size = size / modules as f64;
+    for (index, color) in code.to_colors().into_iter().enumerate() {
+        if color == Color::Dark {
+            let (column, row) = (index % modules, index / modules);
+            fill_rectangle(
+                layer,
+                x + column as f64 * module_size,
+                y + size - (row + 1) as f64 * module_size,
+                module_size,
+                module_size,
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{code128_modules, render_labels, Payloads, DEWAR_LABEL_SIZE, PUCK_LABEL_SIZE};
+    use crate::{
+        api::{NodeId, NodeType},
+        export::{DewarManifest, PuckManifest, ShipmentManifest},
+    };
+    use async_graphql::ID;
+
+    /// The bars and spaces of the Code 128 symbol which selects character set B
+    const START_B: [u8; 11] = [1, 1, 0, 1, 0, 0, 1, 0, 0, 0, 0];
+
+    fn puck(id: u32, code: Option<&str>) -> PuckManifest {
+        PuckManifest {
+            id,
+            code: code.map(str::to_string),
+            pins: Vec::new(),
+        }
+    }
+
+    fn dewar(
+        id: u32,
+        code: Option<&str>,
+        facility_code: Option<&str>,
+        pucks: Vec<PuckManifest>,
+    ) -> DewarManifest {
+        DewarManifest {
+            id,
+            code: code.map(str::to_string),
+            facility_code: facility_code.map(str::to_string),
+            pucks,
+        }
+    }
+
+    fn shipment(dewars: Vec<DewarManifest>) -> ShipmentManifest {
+        ShipmentManifest {
+            id: 1,
+            name: Some("Shipment 1".to_string()),
+            comments: None,
+            proposal_id: 2,
+            proposal: "mx12345".to_string(),
+            lab_contact: None,
+            dewars,
+        }
+    }
+
+    /// Reads the width and height, in millimetres, of each page of a PDF in the order written
+    fn page_sizes(pdf: &[u8]) -> Vec<(f64, f64)> {
+        const POINTS_PER_MM: f64 = 72.0 / 25.4;
+        let pdf = String::from_utf8_lossy(pdf);
+        pdf.split("/MediaBox")
+            .skip(1)
+            .map(|rest| {
+                let (start, end) = (rest.find('[').unwrap(), rest.find(']').unwrap());
+                let bounds = rest[start + 1..end]
+                    .split_whitespace()
+                    .map(|value| value.parse::<f64>().unwrap())
+                    .collect::<Vec<_>>();
+                (
+                    (bounds[2] - bounds[0]) / POINTS_PER_MM,
+                    (bounds[3] - bounds[1]) / POINTS_PER_MM,
+                )
+            })
+            .collect()
+    }
+
+    fn assert_page_sizes(pdf: &[u8], expected: &[(f64, f64)]) {
+        let sizes = page_sizes(pdf);
+        assert_eq!(sizes.len(), expected.len(), "{:?}", sizes);
+        for (size, expected) in sizes.iter().zip(expected) {
+            assert!(
+                (size.0 - expected.0).abs() < 0.01 && (size.1 - expected.1).abs() < 0.01,
+                "{:?} != {:?}",
+                size,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn labels_dewars_with_their_facility_code_then_barcode_then_node_id() {
+        assert_eq!(
+            Payloads::of_dewar(&dewar(3, Some("DLS-1"), Some("DLS-MX-0001"), Vec::new())),
+            Payloads {
+                barcode: "DLS-MX-0001".to_string(),
+                qr_code: ID("Dewar:3".to_string()),
+            }
+        );
+        assert_eq!(
+            Payloads::of_dewar(&dewar(3, Some("DLS-1"), None, Vec::new())).barcode,
+            "DLS-1"
+        );
+        assert_eq!(
+            Payloads::of_dewar(&dewar(3, None, None, Vec::new())).barcode,
+            "Dewar:3"
+        );
+    }
+
+    #[test]
+    fn labels_pucks_with_their_barcode_then_node_id() {
+        assert_eq!(
+            Payloads::of_puck(&puck(4, Some("CPS-1"))),
+            Payloads {
+                barcode: "CPS-1".to_string(),
+                qr_code: ID("Puck:4".to_string()),
+            }
+        );
+        assert_eq!(Payloads::of_puck(&puck(4, None)).barcode, "Puck:4");
+    }
+
+    #[test]
+    fn encodes_node_ids_in_qr_codes() {
+        let payloads = Payloads::of_dewar(&dewar(3, Some("DLS-1"), None, Vec::new()));
+        assert_eq!(
+            NodeId::try_from(&payloads.qr_code).unwrap(),
+            NodeId::new(NodeType::Dewar, 3)
+        );
+        let payloads = Payloads::of_puck(&puck(4, Some("CPS-1")));
+        assert_eq!(
+            NodeId::try_from(&payloads.qr_code).unwrap(),
+            NodeId::new(NodeType::Puck, 4)
+        );
+    }
+
+    #[test]
+    fn encodes_barcodes_in_character_set_b() {
+        let modules = code128_modules("dls-mx-0001").unwrap();
+        assert_eq!(modules[..START_B.len()], START_B);
+        // A start symbol, a symbol per character and a checksum, each of 11 modules, then a stop
+        // symbol of 13 modules
+        assert_eq!(modules.len(), 11 * (1 + 11 + 1) + 13);
+    }
+
+    #[test]
+    fn sizes_each_page_to_its_label() {
+        let manifest = shipment(vec![
+            dewar(
+                3,
+                Some("DLS-1"),
+                Some("DLS-MX-0001"),
+                vec![puck(4, Some("CPS-1")), puck(5, None)],
+            ),
+            dewar(6, None, None, vec![puck(7, Some("CPS-3"))]),
+        ]);
+        assert_page_sizes(
+            &render_labels(&manifest).unwrap(),
+            &[
+                DEWAR_LABEL_SIZE,
+                DEWAR_LABEL_SIZE,
+                PUCK_LABEL_SIZE,
+                PUCK_LABEL_SIZE,
+                PUCK_LABEL_SIZE,
+            ],
+        );
+    }
+
+    #[test]
+    fn renders_a_blank_dewar_label_for_an_empty_shipment() {
+        assert_page_sizes(
+            &render_labels(&shipment(Vec::new())).unwrap(),
+            &[DEWAR_LABEL_SIZE],
+        );
+    }
+}
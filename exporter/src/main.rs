@@ -1,42 +1,208 @@
 #[forbid(unsafe_code)]
 #[warn(missing_docs)]
 mod api;
+mod api_keys;
+mod auth;
 mod broker;
+mod compatibility;
+mod config;
+mod document;
+mod export;
+mod health;
+mod labels;
+mod manifest;
+mod metrics;
+mod poller;
+mod schema_diff;
+mod soakdb;
+mod tables;
+mod webhooks;
 
-use self::api::{RootMutation, RootQuery, RootSchema, RootSubscription};
-use async_graphql::{http::GraphiQLSource, Schema};
-use async_graphql_axum::{GraphQLRequest, GraphQLResponse, GraphQLSubscription};
+use self::{
+    api::{
+        ProposalAccess, ProposalAccessCache, RootMutation, RootQuery, RootSchema, RootSubscription,
+        ShipmentEvent,
+    },
+    auth::{AuthError, Authenticator, Caller, Scope},
+    broker::{InMemoryEventBroker, RedisEventBroker, SharedEventBroker},
+    compatibility::{SchemaCheck, SchemaCheckOutcome},
+    config::{
+        BrokerBackend, BrokerSettings, Config, LogLevel, LoggingSettings, MetricsSettings,
+        ShipmentDefaults,
+    },
+    document::ShipmentDocument,
+    export::{ExportFormat, ShipmentFile, ShipmentManifest},
+    manifest::{Manifest, ManifestError, ManifestFormat},
+    webhooks::WebhookConfig,
+};
+use async_graphql::{
+    http::{GraphiQLSource, ALL_WEBSOCKET_PROTOCOLS},
+    Data, Schema, SchemaBuilder,
+};
+use async_graphql_axum::{GraphQLProtocol, GraphQLRequest, GraphQLResponse, GraphQLWebSocket};
 use axum::{
-    response::{Html, IntoResponse},
+    extract::{Path, WebSocketUpgrade},
+    headers::{authorization::Bearer, Authorization},
+    http::{
+        header::{AUTHORIZATION, CONTENT_TYPE},
+        HeaderValue, Method, StatusCode,
+    },
+    response::{Html, IntoResponse, Response},
     routing::get,
-    Extension, Router, Server,
+    Extension, Router, Server, TypedHeader,
 };
-use clap::Parser;
-use sea_orm::{Database, DatabaseConnection};
+use clap::{Parser, Subcommand, ValueEnum};
+use sea_orm::{ConnectOptions, Database, DatabaseConnection};
 use std::{
     fs::File,
     io::Write,
-    net::{Ipv4Addr, SocketAddr, SocketAddrV4},
-    path::PathBuf,
+    net::{Ipv4Addr, SocketAddr},
+    path::{Path as FilePath, PathBuf},
+    sync::Arc,
+    time::Duration,
 };
+use tower_http::cors::{AllowOrigin, CorsLayer};
+
+/// Loads the configuration, exiting with the error if it cannot be read
+fn load_config(path: Option<&FilePath>, database_url: Option<String>) -> Config {
+    let mut config = Config::load(path).unwrap_or_else(|err| {
+        eprintln!("{}", err);
+        std::process::exit(1);
+    });
+    if let Some(database_url) = database_url {
+        config.database.url = Some(database_url);
+    }
+    config
+}
 
-async fn setup_database() -> DatabaseConnection {
-    let database_url = std::env::var("DATABASE_URL").expect("");
-    Database::connect(&database_url)
+fn setup_logging(settings: &LoggingSettings) {
+    tracing_subscriber::fmt()
+        .with_max_level(settings.level)
+        .init();
+}
+
+async fn setup_database(config: &Config) -> DatabaseConnection {
+    let database_url = config.database.url.clone().unwrap_or_else(|| {
+        panic!("database.url must be set in the config file, or supplied by DATABASE_URL or --database-url")
+    });
+    let mut options = ConnectOptions::new(database_url);
+    options.sqlx_logging(config.logging.sql_statements);
+    if let Some(max_connections) = config.database.max_connections {
+        options.max_connections(max_connections);
+    }
+    if let Some(min_connections) = config.database.min_connections {
+        options.min_connections(min_connections);
+    }
+    if let Some(connect_timeout) = config.database.connect_timeout {
+        options.connect_timeout(Duration::from_secs(connect_timeout));
+    }
+    let mut database = Database::connect(options)
         .await
-        .expect("The DATABASE_URL environment variable must point to a live instance of ISPyB")
+        .expect("The database URL must point to a live instance of ISPyB");
+    database.set_metric_callback(metrics::record_query);
+    let schema_check = config.database.schema_check;
+    if schema_check != SchemaCheck::Off {
+        let report = compatibility::check_compatibility(&database)
+            .await
+            .expect("Could not read the ISPyB schema");
+        match schema_check.outcome(&report) {
+            SchemaCheckOutcome::Start => {}
+            SchemaCheckOutcome::StartWithWarnings => tracing::warn!("{}", report),
+            SchemaCheckOutcome::Refuse => {
+                tracing::error!("{}", report);
+                panic!("The ISPyB schema is incompatible with the exporter, refusing to start");
+            }
+        }
+    }
+    tables::setup_tables(&database)
+        .await
+        .expect("Could not create the exporter tables");
+    database
 }
 
-async fn setup_api(
-    database: DatabaseConnection,
-) -> Schema<RootQuery, RootMutation, RootSubscription> {
+/// The Redis pub/sub channel on which shipment events are shared between replicas
+const SHIPMENT_EVENT_CHANNEL: &str = "xchemlab-exporter-shipment-events";
+
+async fn setup_event_broker(settings: &BrokerSettings) -> SharedEventBroker<ShipmentEvent> {
+    match (settings.backend, &settings.redis_url) {
+        (BrokerBackend::Redis, Some(redis_url)) => Arc::new(
+            RedisEventBroker::<ShipmentEvent>::connect(
+                redis_url,
+                SHIPMENT_EVENT_CHANNEL.to_string(),
+                settings.event_capacity,
+            )
+            .await
+            .unwrap_or_else(|err| panic!("Could not connect to Redis at {}: {}", redis_url, err)),
+        ),
+        (BrokerBackend::Redis, None) => {
+            panic!("broker.redis_url must be set to use the redis backend")
+        }
+        (BrokerBackend::InMemory, _) => Arc::new(InMemoryEventBroker::<ShipmentEvent>::new(
+            settings.event_capacity,
+        )),
+    }
+}
+
+/// The standard introspection query, the response to which describes the schema as JSON
+const INTROSPECTION_QUERY: &str = include_str!("introspection.graphql");
+
+/// Prints each problem with a manifest and exits with a failure status
+fn exit_with_manifest_errors(errors: Vec<ManifestError>) -> ! {
+    for error in errors {
+        eprintln!("{}", error);
+    }
+    std::process::exit(1);
+}
+
+/// Checks the proteins of a manifest against the proposal and, unless this is a dry run, creates
+/// a shipment from it
+async fn import_manifest(manifest: Manifest, proposal_id: u32, dry_run: bool, config: Config) {
+    let database = setup_database(&config).await;
+    let errors = manifest
+        .check_proteins(proposal_id, &database)
+        .await
+        .expect("Could not look up proteins");
+    if !errors.is_empty() {
+        exit_with_manifest_errors(errors);
+    }
+    if !dry_run {
+        let event_broker = setup_event_broker(&config.broker).await;
+        let (shipment, _) = api::insert_shipment(
+            proposal_id,
+            manifest.into_dewars(),
+            &config.shipments,
+            &database,
+            &event_broker,
+        )
+        .await
+        .unwrap_or_else(|err| panic!("Could not create shipment: {}", err.message));
+        event_broker.flush().await;
+        println!("Created shipment {}", shipment.shipping_id);
+    }
+}
+
+fn schema_builder() -> SchemaBuilder<RootQuery, RootMutation, RootSubscription> {
     Schema::build(
         RootQuery::default(),
         RootMutation::default(),
         RootSubscription::default(),
     )
-    .data(database)
-    .finish()
+}
+
+async fn setup_api(
+    database: DatabaseConnection,
+    event_broker: SharedEventBroker<ShipmentEvent>,
+    shipment_defaults: ShipmentDefaults,
+    metrics_settings: MetricsSettings,
+) -> Schema<RootQuery, RootMutation, RootSubscription> {
+    schema_builder()
+        .data(database)
+        .data(event_broker)
+        .data(shipment_defaults)
+        .extension(metrics::GraphQLMetrics::new(
+            metrics_settings.operation_names,
+        ))
+        .finish()
 }
 
 async fn graphiql() -> impl IntoResponse {
@@ -48,24 +214,130 @@ async fn graphiql() -> impl IntoResponse {
     )
 }
 
-async fn graphql_handler(schema: Extension<RootSchema>, req: GraphQLRequest) -> GraphQLResponse {
-    schema.execute(req.into_inner()).await.into()
+async fn authenticate_request(
+    authenticator: &Authenticator,
+    authorization: Option<TypedHeader<Authorization<Bearer>>>,
+) -> Result<Caller, AuthError> {
+    match authorization {
+        Some(TypedHeader(authorization)) => authenticator.authenticate(authorization.token()).await,
+        None => Err(AuthError::MissingToken),
+    }
+}
+
+async fn graphql_handler(
+    schema: Extension<RootSchema>,
+    authenticator: Extension<Arc<Authenticator>>,
+    authorization: Option<TypedHeader<Authorization<Bearer>>>,
+    req: GraphQLRequest,
+) -> Response {
+    match authenticate_request(&authenticator, authorization).await {
+        Ok(caller) => GraphQLResponse::from(
+            schema
+                .execute(
+                    req.into_inner()
+                        .data(caller)
+                        .data(ProposalAccessCache::default()),
+                )
+                .await,
+        )
+        .into_response(),
+        Err(err) => (StatusCode::UNAUTHORIZED, err.to_string()).into_response(),
+    }
 }
 
-async fn setup_router<Q, M, S>(schema: Schema<Q, M, S>) -> Router
-where
-    Q: async_graphql::ObjectType + 'static,
-    M: async_graphql::ObjectType + 'static,
-    S: async_graphql::SubscriptionType + 'static,
-{
-    Router::new()
+async fn graphql_ws_handler(
+    Extension(schema): Extension<RootSchema>,
+    Extension(authenticator): Extension<Arc<Authenticator>>,
+    protocol: GraphQLProtocol,
+    websocket: WebSocketUpgrade,
+) -> Response {
+    websocket
+        .protocols(ALL_WEBSOCKET_PROTOCOLS)
+        .on_upgrade(move |stream| {
+            GraphQLWebSocket::new(stream, schema, protocol)
+                .on_connection_init(move |payload| async move {
+                    let caller = authenticator.authenticate_connection(&payload).await?;
+                    let mut data = Data::default();
+                    data.insert(caller);
+                    data.insert(ProposalAccessCache::default());
+                    Ok(data)
+                })
+                .serve()
+        })
+}
+
+/// Serves documents describing a shipment, such as `manifest.csv`, `manifest.json`,
+/// `manifest.pdf` and `labels.pdf`, to callers who may access its proposal
+async fn shipment_file_handler(
+    Extension(database): Extension<DatabaseConnection>,
+    Extension(authenticator): Extension<Arc<Authenticator>>,
+    authorization: Option<TypedHeader<Authorization<Bearer>>>,
+    Path((shipment_id, file_name)): Path<(u32, String)>,
+) -> Response {
+    let caller = match authenticate_request(&authenticator, authorization).await {
+        Ok(caller) => caller,
+        Err(err) => return (StatusCode::UNAUTHORIZED, err.to_string()).into_response(),
+    };
+    let Some(file) = ShipmentFile::from_file_name(&file_name) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let access = match ProposalAccess::of(&caller, &database).await {
+        Ok(access) => access,
+        Err(err) => return (StatusCode::FORBIDDEN, err.message).into_response(),
+    };
+    match ShipmentManifest::load(shipment_id, &access, &database).await {
+        Ok(Some(manifest)) => match manifest.render_file(file) {
+            Ok(body) => ([(CONTENT_TYPE, file.content_type())], body).into_response(),
+            Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+        },
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
+
+/// Permits browsers on the listed origins, or any origin if `*` is listed, to call the API
+fn cors_layer(origins: &[String]) -> CorsLayer {
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().map(|origin| {
+            HeaderValue::from_str(origin)
+                .unwrap_or_else(|_| panic!("'{}' is not a valid CORS origin", origin))
+        }))
+    };
+    CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST])
+        .allow_headers([AUTHORIZATION, CONTENT_TYPE])
+}
+
+async fn setup_router(
+    schema: RootSchema,
+    authenticator: Authenticator,
+    database: DatabaseConnection,
+    event_broker: SharedEventBroker<ShipmentEvent>,
+    cors_origins: &[String],
+) -> Router {
+    let router = Router::new()
         .route("/", get(graphiql).post(graphql_handler))
-        .route_service("/ws", GraphQLSubscription::new(schema.clone()))
+        .route("/ws", get(graphql_ws_handler))
+        .route("/shipments/:id/:file", get(shipment_file_handler))
+        .route("/healthz", get(health::healthz))
+        .route("/readyz", get(health::readyz))
+        .route("/version", get(health::version))
+        .route("/metrics", get(metrics::metrics_handler))
         .layer(Extension(schema))
+        .layer(Extension(Arc::new(authenticator)))
+        .layer(Extension(database))
+        .layer(Extension(event_broker));
+    if cors_origins.is_empty() {
+        router
+    } else {
+        router.layer(cors_layer(cors_origins))
+    }
 }
 
-async fn serve(router: Router, port: u16) {
-    let socket_addr: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, port));
+async fn serve(router: Router, socket_addr: SocketAddr) {
     println!("GraphiQL IDE: {}", socket_addr);
     Server::bind(&socket_addr)
         .serve(router.into_make_service())
@@ -75,18 +347,154 @@ async fn serve(router: Router, port: u16) {
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
-enum Cli {
+struct Cli {
+    /// The TOML config file, the values of which are overridden by environment variables and flags.
+    #[arg(long, env = "EXPORTER_CONFIG", global = true)]
+    config: Option<PathBuf>,
+    /// The URL of the ISPyB database, overriding the config file and DATABASE_URL.
+    #[arg(long, global = true)]
+    database_url: Option<String>,
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
     /// Starts a webserver serving the GraphQL API
     Serve(ServeArgs),
     /// Prints the GraphQL API to stdout
     Schema(SchemaArgs),
+    /// Manages the API keys with which services authenticate
+    #[command(name = "apikey", subcommand)]
+    ApiKey(ApiKeyCommand),
+    /// Creates a shipment from a CSV or XLSX manifest, with a row for each pin
+    ImportManifest(ImportManifestArgs),
+    /// Creates a shipment of the crystals mounted in pucks, as recorded in a SoakDB database
+    #[command(name = "import-soakdb")]
+    ImportSoakDb(ImportSoakDbArgs),
+    /// Writes the manifest of a shipment, as CSV, JSON or a PDF packing list
+    Export(ExportArgs),
+    /// Writes a PDF of barcode labels for each dewar and puck in a shipment
+    Labels(LabelsArgs),
+    /// Writes a shipment as a portable JSON document, from which it can be recreated
+    ExportJson(ExportJsonArgs),
+    /// Recreates a shipment from a JSON document, as written by export-json
+    ImportJson(ImportJsonArgs),
+    /// Prints the JSON Schema of the documents written by export-json
+    DocumentSchema(DocumentSchemaArgs),
+    /// Inspects the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    /// Probes a running exporter, exiting unsuccessfully if it is not ready, for use by container health checks
+    Healthcheck(HealthcheckArgs),
 }
 
+/// Flags overriding the values of the config file and environment variables
 #[derive(Debug, Parser)]
 struct ServeArgs {
-    /// The port number to serve on.
-    #[arg(short, long, default_value_t = 80)]
-    port: u16,
+    /// The address and port to serve on.
+    #[arg(long)]
+    bind_address: Option<SocketAddr>,
+    /// The port number to serve on, on the configured address.
+    #[arg(short, long)]
+    port: Option<u16>,
+    /// An origin from which browsers may call the API, or * for any origin. May be supplied multiple times.
+    #[arg(long)]
+    cors_origin: Vec<String>,
+    /// The number of events buffered for each subscriber before it misses events.
+    #[arg(long)]
+    event_capacity: Option<usize>,
+    /// The issuer which access tokens must be issued by.
+    #[arg(long)]
+    oidc_issuer: Option<String>,
+    /// The audience which access tokens must be issued for.
+    #[arg(long)]
+    oidc_audience: Option<String>,
+    /// The URL or file path of the JSON Web Key Set used to verify access tokens.
+    #[arg(long)]
+    oidc_jwks: Option<String>,
+    /// The role which grants staff access to every proposal.
+    #[arg(long)]
+    oidc_staff_role: Option<String>,
+    /// The URL of a Redis instance used to share events between replicas. If not configured events are only delivered within this process.
+    #[arg(long)]
+    redis_url: Option<String>,
+    /// The interval, in seconds, at which to poll ISPyB for changes made outside of the exporter. If not configured ISPyB is not polled.
+    #[arg(long)]
+    poll_interval: Option<u64>,
+    /// The age, in days, of the oldest shipments polled for changes.
+    #[arg(long)]
+    poll_lookback: Option<u64>,
+    /// A URL to which shipment events are POSTed, replacing those configured. May be supplied multiple times.
+    #[arg(long)]
+    webhook_url: Vec<String>,
+    /// The secret with which webhook payloads are signed.
+    #[arg(long)]
+    webhook_secret: Option<String>,
+    /// The number of attempts made to deliver each webhook before it is marked as failed.
+    #[arg(long)]
+    webhook_max_attempts: Option<u32>,
+    /// How to react to an ISPyB schema which differs from that the exporter was built against.
+    #[arg(long, value_enum)]
+    schema_check: Option<SchemaCheck>,
+    /// The most verbose level of log messages written.
+    #[arg(long, value_enum)]
+    log_level: Option<LogLevel>,
+}
+
+impl ServeArgs {
+    /// Overrides the configured values with those supplied as flags
+    fn apply_to(self, config: &mut Config) {
+        if let Some(bind_address) = self.bind_address {
+            config.server.bind_address = bind_address;
+        }
+        if let Some(port) = self.port {
+            config.server.bind_address.set_port(port);
+        }
+        if !self.cors_origin.is_empty() {
+            config.server.cors_origins = self.cors_origin;
+        }
+        if let Some(event_capacity) = self.event_capacity {
+            config.broker.event_capacity = event_capacity;
+        }
+        if let Some(oidc_issuer) = self.oidc_issuer {
+            config.auth.oidc_issuer = Some(oidc_issuer);
+        }
+        if let Some(oidc_audience) = self.oidc_audience {
+            config.auth.oidc_audience = Some(oidc_audience);
+        }
+        if let Some(oidc_jwks) = self.oidc_jwks {
+            config.auth.oidc_jwks = Some(oidc_jwks);
+        }
+        if let Some(oidc_staff_role) = self.oidc_staff_role {
+            config.auth.oidc_staff_role = Some(oidc_staff_role);
+        }
+        if let Some(redis_url) = self.redis_url {
+            config.broker.backend = BrokerBackend::Redis;
+            config.broker.redis_url = Some(redis_url);
+        }
+        if let Some(poll_interval) = self.poll_interval {
+            config.poller.interval = Some(poll_interval);
+        }
+        if let Some(poll_lookback) = self.poll_lookback {
+            config.poller.lookback = poll_lookback;
+        }
+        if !self.webhook_url.is_empty() {
+            config.webhooks.urls = self.webhook_url;
+        }
+        if let Some(webhook_secret) = self.webhook_secret {
+            config.webhooks.secret = Some(webhook_secret);
+        }
+        if let Some(webhook_max_attempts) = self.webhook_max_attempts {
+            config.webhooks.max_attempts = webhook_max_attempts;
+        }
+        if let Some(schema_check) = self.schema_check {
+            config.database.schema_check = schema_check;
+        }
+        if let Some(log_level) = self.log_level {
+            config.logging.level = log_level;
+        }
+    }
 }
 
 #[derive(Debug, Parser)]
@@ -94,23 +502,292 @@ struct SchemaArgs {
     /// The file path to write the schema to. If not supplied the schema will be printed to stdout.
     #[arg(short, long)]
     path: Option<PathBuf>,
+    /// The format in which to write the schema.
+    #[arg(short, long, value_enum, default_value_t = SchemaFormat::Sdl)]
+    format: SchemaFormat,
+    /// A committed SDL schema to compare against, failing if the served schema changes any of its types.
+    #[arg(long, conflicts_with_all = ["path", "format"])]
+    check: Option<PathBuf>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SchemaFormat {
+    /// GraphQL schema definition language
+    Sdl,
+    /// The response to the introspection query
+    Json,
+}
+
+#[derive(Debug, Subcommand)]
+enum ConfigCommand {
+    /// Validates the configuration, as overridden by environment variables, listing every problem
+    Check,
+}
+
+#[derive(Debug, Subcommand)]
+enum ApiKeyCommand {
+    /// Creates an API key, printing it to stdout. The key cannot be retrieved later.
+    Create(ApiKeyCreateArgs),
+    /// Lists all API keys, including those which have been revoked
+    List,
+    /// Revokes an API key, such that it is no longer accepted
+    Revoke(ApiKeyRevokeArgs),
+}
+
+#[derive(Debug, Parser)]
+struct ApiKeyCreateArgs {
+    /// A name identifying the service which will hold the key.
+    #[arg(short, long)]
+    name: String,
+    /// An operation the key permits, of which either permits reading. May be supplied multiple
+    /// times. Keys are not restricted to particular proposals, so may access every proposal.
+    #[arg(short, long, value_enum)]
+    scope: Vec<Scope>,
+}
+
+#[derive(Debug, Parser)]
+struct ApiKeyRevokeArgs {
+    /// The id of the key to revoke.
+    id: u32,
+}
+
+#[derive(Debug, Parser)]
+struct ImportManifestArgs {
+    /// The CSV or XLSX manifest, the format of which is inferred from the extension.
+    path: PathBuf,
+    /// The proposal under which to create the shipment.
+    #[arg(long)]
+    proposal_id: u32,
+    /// Validate the manifest without creating a shipment.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Parser)]
+struct ImportSoakDbArgs {
+    /// The SoakDB SQLite file.
+    path: PathBuf,
+    /// The proposal under which to create the shipment.
+    #[arg(long)]
+    proposal_id: u32,
+    /// The barcode of the dewar in which the pucks are shipped.
+    #[arg(long)]
+    dewar: String,
+    /// The acronym of the protein, registered against the proposal, of which the crystals are.
+    #[arg(long)]
+    protein_acronym: Option<String>,
+    /// Validate the SoakDB database without creating a shipment.
+    #[arg(long)]
+    dry_run: bool,
+}
+
+#[derive(Debug, Parser)]
+struct ExportArgs {
+    /// The id of the shipment to export.
+    shipment_id: u32,
+    /// The format in which to write the manifest.
+    #[arg(short, long, value_enum, default_value_t = ExportFormat::Csv)]
+    format: ExportFormat,
+    /// The file path to write the manifest to. If not supplied the manifest will be written to stdout.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+struct LabelsArgs {
+    /// The id of the shipment to label.
+    shipment_id: u32,
+    /// The file path to write the labels to. If not supplied the labels will be written to stdout.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+struct ExportJsonArgs {
+    /// The id of the shipment to export.
+    shipment_id: u32,
+    /// The file path to write the document to. If not supplied the document will be printed to stdout.
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+struct ImportJsonArgs {
+    /// The shipment document to import.
+    path: PathBuf,
+    /// The proposal under which to create the shipment. If not supplied the proposal is found by the code and number in the document.
+    #[arg(long)]
+    proposal_id: Option<u32>,
+}
+
+#[derive(Debug, Parser)]
+struct DocumentSchemaArgs {
+    /// The file path to write the schema to. If not supplied the schema will be printed to stdout.
+    #[arg(short, long)]
+    path: Option<PathBuf>,
+}
+
+#[derive(Debug, Parser)]
+struct HealthcheckArgs {
+    /// The URL of the endpoint to probe. If not supplied the configured port is probed on localhost.
+    #[arg(long)]
+    url: Option<String>,
+    /// Probe whether the process is alive, rather than whether it is ready to serve requests.
+    #[arg(long)]
+    live: bool,
 }
 
 #[tokio::main]
 async fn main() {
-    let args = Cli::parse();
-
-    match args {
-        Cli::Serve(args) => {
-            let database = setup_database().await;
-            let schema = setup_api(database).await;
-            let router = setup_router(schema).await;
-            serve(router, args.port).await;
-        }
-        Cli::Schema(args) => {
-            let database = setup_database().await;
-            let schema = setup_api(database).await;
-            let schema_string = schema.sdl();
+    let Cli {
+        config: config_path,
+        database_url,
+        command,
+    } = Cli::parse();
+    let config = || {
+        let config = load_config(config_path.as_deref(), database_url.clone());
+        setup_logging(&config.logging);
+        config
+    };
+
+    match command {
+        Command::Serve(args) => {
+            let mut config = config();
+            args.apply_to(&mut config);
+            let problems = config.validate();
+            if !problems.is_empty() {
+                for problem in problems {
+                    eprintln!("{}", problem);
+                }
+                std::process::exit(1);
+            }
+            let database = setup_database(&config).await;
+            let event_broker = setup_event_broker(&config.broker).await;
+            if let Some(poll_interval) = config.poller.interval {
+                tokio::spawn(poller::poll_for_changes(
+                    database.clone(),
+                    event_broker.clone(),
+                    Duration::from_secs(poll_interval),
+                    Duration::from_secs(config.poller.lookback * 24 * 60 * 60),
+                ));
+            }
+            if let Some(webhook_secret) = config.webhooks.secret {
+                if !config.webhooks.urls.is_empty() {
+                    tokio::spawn(webhooks::dispatch_webhooks(
+                        database.clone(),
+                        event_broker.clone(),
+                        WebhookConfig {
+                            urls: config.webhooks.urls,
+                            secret: webhook_secret,
+                            max_attempts: config.webhooks.max_attempts,
+                            retry_delay: Duration::from_secs(10),
+                        },
+                    ));
+                }
+            }
+            let schema = setup_api(
+                database.clone(),
+                event_broker.clone(),
+                config.shipments,
+                config.metrics,
+            )
+            .await;
+            let oidc_jwks = config.auth.oidc_jwks.expect("auth.oidc_jwks is validated");
+            let authenticator = Authenticator::load(
+                database.clone(),
+                &oidc_jwks,
+                config
+                    .auth
+                    .oidc_issuer
+                    .expect("auth.oidc_issuer is validated"),
+                config
+                    .auth
+                    .oidc_audience
+                    .expect("auth.oidc_audience is validated"),
+                config.auth.oidc_staff_role,
+            )
+            .await
+            .unwrap_or_else(|err| panic!("Could not load {}: {}", oidc_jwks, err));
+            let router = setup_router(
+                schema,
+                authenticator,
+                database,
+                event_broker,
+                &config.server.cors_origins,
+            )
+            .await;
+            serve(router, config.server.bind_address).await;
+        }
+        Command::Config(ConfigCommand::Check) => {
+            let problems = config().validate();
+            if problems.is_empty() {
+                println!("The configuration is valid");
+            } else {
+                for problem in problems.iter() {
+                    eprintln!("{}", problem);
+                }
+                eprintln!("The configuration has {} problems", problems.len());
+                std::process::exit(1);
+            }
+        }
+        Command::Healthcheck(args) => {
+            let url = args.url.unwrap_or_else(|| {
+                let mut address = config().server.bind_address;
+                if address.ip().is_unspecified() {
+                    address.set_ip(Ipv4Addr::LOCALHOST.into());
+                }
+                format!(
+                    "http://{}/{}",
+                    address,
+                    if args.live { "healthz" } else { "readyz" }
+                )
+            });
+            if let Err(err) = health::probe(&url).await {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            }
+        }
+        Command::Schema(args) => {
+            let schema = schema_builder().finish();
+            if let Some(check) = args.check {
+                let committed = std::fs::read_to_string(&check)
+                    .unwrap_or_else(|err| panic!("Could not read {}: {}", check.display(), err));
+                let served = schema.sdl();
+                let changes = schema_diff::diff_schemas(&committed, &served)
+                    .unwrap_or_else(|err| panic!("Could not parse {}: {}", check.display(), err));
+                for change in changes.iter() {
+                    println!("{}", change);
+                }
+                if changes.is_empty() {
+                    if committed == served {
+                        println!("{} is up to date", check.display());
+                    } else {
+                        println!(
+                            "{} is equivalent to the served schema, though their text differs",
+                            check.display()
+                        );
+                    }
+                    return;
+                }
+                let breaking = changes.iter().filter(|change| change.is_breaking()).count();
+                eprintln!(
+                    "{} differs from the served schema: {} breaking and {} non-breaking changes",
+                    check.display(),
+                    breaking,
+                    changes.len() - breaking
+                );
+                std::process::exit(schema_diff::exit_status(&changes));
+            }
+            let schema_string = match args.format {
+                SchemaFormat::Sdl => schema.sdl(),
+                SchemaFormat::Json => {
+                    let response = schema.execute(INTROSPECTION_QUERY).await;
+                    if response.is_err() {
+                        panic!("Could not introspect schema: {:?}", response.errors);
+                    }
+                    serde_json::to_string_pretty(&response).unwrap()
+                }
+            };
             if let Some(path) = args.path {
                 let mut file = File::create(path).unwrap();
                 file.write_all(schema_string.as_bytes()).unwrap();
@@ -118,5 +795,226 @@ async fn main() {
                 println!("{}", schema_string);
             }
         }
+        Command::ImportManifest(args) => {
+            let content = std::fs::read(&args.path)
+                .unwrap_or_else(|err| panic!("Could not read {}: {}", args.path.display(), err));
+            let manifest = Manifest::parse(&content, ManifestFormat::from_file_name(&args.path))
+                .unwrap_or_else(|errors| exit_with_manifest_errors(errors));
+            println!(
+                "Manifest contains {} dewars, {} pucks and {} pins",
+                manifest.dewar_count(),
+                manifest.puck_count(),
+                manifest.pin_count()
+            );
+            import_manifest(manifest, args.proposal_id, args.dry_run, config()).await;
+        }
+        Command::ImportSoakDb(args) => {
+            let crystals = soakdb::read_mounted_crystals(&args.path)
+                .unwrap_or_else(|err| panic!("Could not read {}: {}", args.path.display(), err));
+            let manifest = soakdb::into_manifest(crystals, args.dewar, args.protein_acronym)
+                .unwrap_or_else(|errors| exit_with_manifest_errors(errors));
+            println!(
+                "SoakDB database contains {} mounted crystals in {} pucks",
+                manifest.pin_count(),
+                manifest.puck_count()
+            );
+            import_manifest(manifest, args.proposal_id, args.dry_run, config()).await;
+        }
+        Command::Export(args) => {
+            let database = setup_database(&config()).await;
+            let manifest =
+                ShipmentManifest::load(args.shipment_id, &ProposalAccess::All, &database)
+                    .await
+                    .expect("Could not load shipment")
+                    .unwrap_or_else(|| panic!("No shipment with id {}", args.shipment_id));
+            let content = manifest
+                .render(args.format)
+                .unwrap_or_else(|err| panic!("Could not export shipment: {}", err));
+            if let Some(path) = args.output {
+                let mut file = File::create(path).unwrap();
+                file.write_all(&content).unwrap();
+            } else {
+                std::io::stdout().write_all(&content).unwrap();
+            }
+        }
+        Command::Labels(args) => {
+            let database = setup_database(&config()).await;
+            let manifest =
+                ShipmentManifest::load(args.shipment_id, &ProposalAccess::All, &database)
+                    .await
+                    .expect("Could not load shipment")
+                    .unwrap_or_else(|| panic!("No shipment with id {}", args.shipment_id));
+            let content = manifest
+                .render_file(ShipmentFile::Labels)
+                .unwrap_or_else(|err| panic!("Could not render labels: {}", err));
+            if let Some(path) = args.output {
+                let mut file = File::create(path).unwrap();
+                file.write_all(&content).unwrap();
+            } else {
+                std::io::stdout().write_all(&content).unwrap();
+            }
+        }
+        Command::ExportJson(args) => {
+            let database = setup_database(&config()).await;
+            let manifest =
+                ShipmentManifest::load(args.shipment_id, &ProposalAccess::All, &database)
+                    .await
+                    .expect("Could not load shipment")
+                    .unwrap_or_else(|| panic!("No shipment with id {}", args.shipment_id));
+            let document = ShipmentDocument::from_manifest(manifest, &database)
+                .await
+                .expect("Could not load proposal");
+            let document_string = serde_json::to_string_pretty(&document).unwrap();
+            if let Some(path) = args.output {
+                let mut file = File::create(path).unwrap();
+                file.write_all(document_string.as_bytes()).unwrap();
+            } else {
+                println!("{}", document_string);
+            }
+        }
+        Command::ImportJson(args) => {
+            let content = std::fs::read_to_string(&args.path)
+                .unwrap_or_else(|err| panic!("Could not read {}: {}", args.path.display(), err));
+            let document =
+                ShipmentDocument::parse(&content).unwrap_or_else(|err| panic!("{}", err));
+            let config = config();
+            let database = setup_database(&config).await;
+            let proposal_id = match args.proposal_id {
+                Some(proposal_id) => proposal_id,
+                None => document
+                    .find_proposal_id(&database)
+                    .await
+                    .expect("Could not look up proposal")
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "No proposal {}{}, supply --proposal-id instead",
+                            document.proposal.code, document.proposal.number
+                        )
+                    }),
+            };
+            let defaults = document.shipment_defaults(&config.shipments);
+            let event_broker = setup_event_broker(&config.broker).await;
+            let (shipment, _) = api::insert_shipment(
+                proposal_id,
+                document.into_dewars(),
+                &defaults,
+                &database,
+                &event_broker,
+            )
+            .await
+            .unwrap_or_else(|err| panic!("Could not create shipment: {}", err.message));
+            event_broker.flush().await;
+            println!("Created shipment {}", shipment.shipping_id);
+        }
+        Command::DocumentSchema(args) => {
+            let schema_string =
+                serde_json::to_string_pretty(&ShipmentDocument::json_schema()).unwrap();
+            if let Some(path) = args.path {
+                let mut file = File::create(path).unwrap();
+                writeln!(file, "{}", schema_string).unwrap();
+            } else {
+                println!("{}", schema_string);
+            }
+        }
+        Command::ApiKey(command) => {
+            let database = setup_database(&config()).await;
+            match command {
+                ApiKeyCommand::Create(args) => {
+                    let (api_key, key) = api_keys::create(args.name, &args.scope, &database)
+                        .await
+                        .expect("Could not create API key");
+                    println!("Created API key {} ({})", api_key.id, api_key.name);
+                    println!("{}", key);
+                }
+                ApiKeyCommand::List => {
+                    for api_key in api_keys::list(&database)
+                        .await
+                        .expect("Could not list API keys")
+                    {
+                        println!(
+                            "{}\t{}\t{}\t{}\t{}",
+                            api_key.id,
+                            api_key.name,
+                            api_key.scopes,
+                            api_key.created_at,
+                            api_key
+                                .revoked_at
+                                .map_or("active".to_string(), |revoked_at| format!(
+                                    "revoked {}",
+                                    revoked_at
+                                ))
+                        );
+                    }
+                }
+                ApiKeyCommand::Revoke(args) => {
+                    if api_keys::revoke(args.id, &database)
+                        .await
+                        .expect("Could not revoke API key")
+                    {
+                        println!("Revoked API key {}", args.id);
+                    } else {
+                        eprintln!("No API key with id {}", args.id);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ServeArgs;
+    use crate::config::{BrokerBackend, Config};
+    use clap::Parser;
+
+    fn apply_flags(config: &mut Config, flags: &[&str]) {
+        ServeArgs::try_parse_from(std::iter::once("serve").chain(flags.iter().copied()))
+            .unwrap()
+            .apply_to(config);
+    }
+
+    #[test]
+    fn overrides_only_the_port_of_the_bind_address() {
+        let mut config = Config::default();
+        config.server.bind_address = "127.0.0.1:8000".parse().unwrap();
+        config.server.cors_origins = vec!["https://example.com".to_string()];
+        apply_flags(&mut config, &["--port", "9000"]);
+        assert_eq!(config.server.bind_address.to_string(), "127.0.0.1:9000");
+        assert_eq!(config.server.cors_origins, vec!["https://example.com"]);
+    }
+
+    #[test]
+    fn applies_the_port_to_a_supplied_bind_address() {
+        let mut config = Config::default();
+        apply_flags(
+            &mut config,
+            &["--bind-address", "127.0.0.1:8000", "--port", "9000"],
+        );
+        assert_eq!(config.server.bind_address.to_string(), "127.0.0.1:9000");
+    }
+
+    #[test]
+    fn leaves_values_without_flags_unchanged() {
+        let mut config = Config::default();
+        config.auth.oidc_issuer = Some("https://authn.example.com".to_string());
+        config.webhooks.max_attempts = 3;
+        apply_flags(&mut config, &[]);
+        assert_eq!(
+            config.auth.oidc_issuer.as_deref(),
+            Some("https://authn.example.com")
+        );
+        assert_eq!(config.webhooks.max_attempts, 3);
+    }
+
+    #[test]
+    fn selects_the_redis_backend_when_a_redis_url_flag_is_supplied() {
+        let mut config = Config::default();
+        apply_flags(&mut config, &["--redis-url", "redis://localhost:6379"]);
+        assert_eq!(config.broker.backend, BrokerBackend::Redis);
+        assert_eq!(
+            config.broker.redis_url.as_deref(),
+            Some("redis://localhost:6379")
+        );
     }
 }
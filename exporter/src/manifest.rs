@@ -0,0 +1,570 @@
+use crate::api::{find_protein, DewarInput, PinInput, PuckInput};
+use calamine::{Reader, Xlsx};
+use sea_orm::{DatabaseConnection, DbErr};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    io::Cursor,
+    path::Path,
+};
+
+/// The columns of a manifest, each of which may be headed by any of the listed names
+const COLUMNS: &[(Column, &[&str])] = &[
+    (Column::Dewar, &["dewar", "dewar barcode"]),
+    (Column::Puck, &["puck", "puck barcode"]),
+    (Column::Position, &["position", "location"]),
+    (Column::Pin, &["pin", "pin barcode"]),
+    (Column::Protein, &["protein", "protein acronym"]),
+    (Column::Sample, &["sample", "sample name"]),
+    (Column::Smiles, &["compound smiles", "smiles"]),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Column {
+    Dewar,
+    Puck,
+    Position,
+    Pin,
+    Protein,
+    Sample,
+    Smiles,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManifestFormat {
+    Csv,
+    Xlsx,
+}
+
+impl ManifestFormat {
+    /// Infers the format from the extension of a file name, defaulting to CSV
+    pub fn from_file_name(file_name: impl AsRef<Path>) -> Self {
+        match file_name
+            .as_ref()
+            .extension()
+            .and_then(|extension| extension.to_str())
+        {
+            Some(extension) if extension.eq_ignore_ascii_case("xlsx") => Self::Xlsx,
+            _ => Self::Csv,
+        }
+    }
+}
+
+/// A problem with a manifest, located by the line of the spreadsheet on which it occurs if known
+#[derive(Debug, Clone)]
+pub struct ManifestError {
+    pub line: Option<u64>,
+    pub message: String,
+}
+
+impl Display for ManifestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "Line {}: {}", line, self.message),
+            None => f.write_str(&self.message),
+        }
+    }
+}
+
+impl ManifestError {
+    fn new(line: Option<u64>, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+/// A pin, as described by a single row of a manifest
+#[derive(Debug, Clone)]
+pub struct ManifestRow {
+    pub line: u64,
+    pub dewar: String,
+    pub puck: String,
+    pub pin: PinInput,
+}
+
+/// The contents of a manifest, validated for consistency but not against ISPyB
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    rows: Vec<ManifestRow>,
+}
+
+impl Manifest {
+    /// Parses a manifest, reporting every malformed row rather than only the first
+    pub fn parse(content: &[u8], format: ManifestFormat) -> Result<Self, Vec<ManifestError>> {
+        let lines = match format {
+            ManifestFormat::Csv => read_csv(content),
+            ManifestFormat::Xlsx => read_xlsx(content),
+        }
+        .map_err(|err| vec![err])?;
+        let mut lines = lines.into_iter();
+        let (header_line, header) = lines
+            .next()
+            .ok_or_else(|| vec![ManifestError::new(None, "The manifest is empty")])?;
+        let columns = locate_columns(header_line, &header)?;
+
+        let mut rows = Vec::new();
+        let mut errors = Vec::new();
+        for (line, cells) in lines {
+            let cell = |column: Column| {
+                columns
+                    .get(&column)
+                    .and_then(|&index| cells.get(index))
+                    .map(|cell| cell.trim())
+                    .filter(|cell| !cell.is_empty())
+                    .map(str::to_string)
+            };
+            if cells.iter().all(|cell| cell.trim().is_empty()) {
+                continue;
+            }
+            let mut require = |column: Column, name: &str| {
+                let value = cell(column);
+                if value.is_none() {
+                    errors.push(ManifestError::new(Some(line), format!("Missing {}", name)));
+                }
+                value
+            };
+            let (Some(dewar), Some(puck), Some(code)) = (
+                require(Column::Dewar, "dewar barcode"),
+                require(Column::Puck, "puck barcode"),
+                require(Column::Pin, "pin barcode"),
+            ) else {
+                continue;
+            };
+
+            rows.push(ManifestRow {
+                line,
+                dewar,
+                puck,
+                pin: PinInput {
+                    code,
+                    location: cell(Column::Position),
+                    name: cell(Column::Sample),
+                    protein_acronym: cell(Column::Protein),
+                    smiles: cell(Column::Smiles),
+                    comments: None,
+                },
+            });
+        }
+
+        match Self::from_rows(rows) {
+            Ok(manifest) if errors.is_empty() => Ok(manifest),
+            Ok(_) => Err(errors),
+            Err(row_errors) => {
+                errors.extend(row_errors);
+                errors.sort_by_key(|error| error.line);
+                Err(errors)
+            }
+        }
+    }
+
+    /// Checks that no puck is in more than one dewar, that no pin appears twice and that each
+    /// position holds at most one pin, reporting every inconsistent row rather than only the first
+    pub fn from_rows(
+        rows: impl IntoIterator<Item = ManifestRow>,
+    ) -> Result<Self, Vec<ManifestError>> {
+        let mut valid_rows = Vec::new();
+        let mut errors = Vec::new();
+        let mut puck_dewars = HashMap::<String, String>::new();
+        let mut pin_lines = HashMap::<String, u64>::new();
+        let mut positions = HashSet::<(String, String)>::new();
+        for row in rows {
+            let line = row.line;
+            match puck_dewars.get(&row.puck) {
+                Some(puck_dewar) if puck_dewar != &row.dewar => {
+                    errors.push(ManifestError::new(
+                        Some(line),
+                        format!(
+                            "Puck {} is already in dewar {}, not {}",
+                            row.puck, puck_dewar, row.dewar
+                        ),
+                    ));
+                    continue;
+                }
+                Some(_) => {}
+                None => {
+                    puck_dewars.insert(row.puck.clone(), row.dewar.clone());
+                }
+            }
+            if let Some(first_line) = pin_lines.get(&row.pin.code) {
+                errors.push(ManifestError::new(
+                    Some(line),
+                    format!(
+                        "Pin {} already appears on line {}",
+                        row.pin.code, first_line
+                    ),
+                ));
+                continue;
+            }
+            pin_lines.insert(row.pin.code.clone(), line);
+            if let Some(location) = &row.pin.location {
+                if !matches!(location.parse::<u32>(), Ok(position) if position > 0) {
+                    errors.push(ManifestError::new(
+                        Some(line),
+                        format!("Position '{}' is not a positive integer", location),
+                    ));
+                    continue;
+                }
+                if !positions.insert((row.puck.clone(), location.clone())) {
+                    errors.push(ManifestError::new(
+                        Some(line),
+                        format!(
+                            "Position {} of puck {} is already occupied",
+                            location, row.puck
+                        ),
+                    ));
+                    continue;
+                }
+            }
+            valid_rows.push(row);
+        }
+
+        if errors.is_empty() {
+            Ok(Self { rows: valid_rows })
+        } else {
+            Err(errors)
+        }
+    }
+
+    /// Checks that every protein acronym is registered against the proposal
+    pub async fn check_proteins(
+        &self,
+        proposal_id: u32,
+        database: &DatabaseConnection,
+    ) -> Result<Vec<ManifestError>, DbErr> {
+        let mut known = HashMap::<&str, bool>::new();
+        let mut errors = Vec::new();
+        for row in self.rows.iter() {
+            let Some(protein_acronym) = row.pin.protein_acronym.as_deref() else {
+                continue;
+            };
+            let exists = match known.get(protein_acronym) {
+                Some(&exists) => exists,
+                None => {
+                    let exists = find_protein(protein_acronym, proposal_id, database)
+                        .await?
+                        .is_some();
+                    known.insert(protein_acronym, exists);
+                    exists
+                }
+            };
+            if !exists {
+                errors.push(ManifestError::new(
+                    Some(row.line),
+                    format!(
+                        "No protein with acronym '{}' in proposal {}",
+                        protein_acronym, proposal_id
+                    ),
+                ));
+            }
+        }
+        Ok(errors)
+    }
+
+    pub fn dewar_count(&self) -> usize {
+        self.rows
+            .iter()
+            .map(|row| &row.dewar)
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    pub fn puck_count(&self) -> usize {
+        self.rows
+            .iter()
+            .map(|row| &row.puck)
+            .collect::<HashSet<_>>()
+            .len()
+    }
+
+    pub fn pin_count(&self) -> usize {
+        self.rows.len()
+    }
+
+    /// Groups the pins into pucks and the pucks into dewars, in the order they first appear
+    pub fn into_dewars(self) -> Vec<DewarInput> {
+        group_pins(
+            self.rows
+                .into_iter()
+                .map(|row| (row.dewar, row.puck, row.pin)),
+        )
+    }
+}
+
+/// Groups pins, each labelled with the codes of its dewar and puck, into pucks and the pucks into
+/// dewars, in the order they first appear
+pub fn group_pins(pins: impl IntoIterator<Item = (String, String, PinInput)>) -> Vec<DewarInput> {
+    let mut dewars = Vec::<DewarInput>::new();
+    for (dewar_code, puck_code, pin) in pins {
+        let dewar = match dewars.iter().position(|dewar| dewar.code == dewar_code) {
+            Some(index) => &mut dewars[index],
+            None => {
+                dewars.push(DewarInput {
+                    code: dewar_code,
+                    pucks: Vec::new(),
+                });
+                dewars.last_mut().unwrap()
+            }
+        };
+        let puck = match dewar.pucks.iter().position(|puck| puck.code == puck_code) {
+            Some(index) => &mut dewar.pucks[index],
+            None => {
+                dewar.pucks.push(PuckInput {
+                    code: puck_code,
+                    pins: Vec::new(),
+                });
+                dewar.pucks.last_mut().unwrap()
+            }
+        };
+        puck.pins.push(pin);
+    }
+    dewars
+}
+
+fn locate_columns(
+    header_line: u64,
+    header: &[String],
+) -> Result<HashMap<Column, usize>, Vec<ManifestError>> {
+    let columns = COLUMNS
+        .iter()
+        .filter_map(|(column, names)| {
+            header
+                .iter()
+                .position(|heading| {
+                    names
+                        .iter()
+                        .any(|name| heading.trim().eq_ignore_ascii_case(name))
+                })
+                .map(|index| (*column, index))
+        })
+        .collect::<HashMap<_, _>>();
+    let missing = [Column::Dewar, Column::Puck, Column::Pin]
+        .into_iter()
+        .filter(|column| !columns.contains_key(column))
+        .map(|column| {
+            let (_, names) = COLUMNS.iter().find(|(other, _)| *other == column).unwrap();
+            ManifestError::new(
+                Some(header_line),
+                format!("Missing a column headed '{}'", names[names.len() - 1]),
+            )
+        })
+        .collect::<Vec<_>>();
+    if missing.is_empty() {
+        Ok(columns)
+    } else {
+        Err(missing)
+    }
+}
+
+/// Reads the cells of each line of a CSV file, alongside the line number
+fn read_csv(content: &[u8]) -> Result<Vec<(u64, Vec<String>)>, ManifestError> {
+    csv::ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(content)
+        .records()
+        .map(|record| {
+            record
+                .map(|record| {
+                    (
+                        record.position().map_or(0, |position| position.line()),
+                        record.iter().map(str::to_string).collect(),
+                    )
+                })
+                .map_err(|err| {
+                    ManifestError::new(
+                        err.position().map(|position| position.line()),
+                        err.to_string(),
+                    )
+                })
+        })
+        .collect()
+}
+
+/// Reads the cells of each row of the first worksheet of an XLSX workbook, alongside the row number
+fn read_xlsx(content: &[u8]) -> Result<Vec<(u64, Vec<String>)>, ManifestError> {
+    let mut workbook = Xlsx::new(Cursor::new(content))
+        .map_err(|err| ManifestError::new(None, format!("Could not open workbook: {}", err)))?;
+    let range = workbook
+        .worksheet_range_at(0)
+        .ok_or_else(|| ManifestError::new(None, "The workbook contains no worksheets"))?
+        .map_err(|err| ManifestError::new(None, format!("Could not read worksheet: {}", err)))?;
+    let first_row = range.start().map_or(0, |(row, _)| row as u64);
+    Ok(range
+        .rows()
+        .enumerate()
+        .map(|(index, cells)| {
+            (
+                first_row + index as u64 + 1,
+                cells.iter().map(|cell| cell.to_string()).collect(),
+            )
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{group_pins, Manifest, ManifestFormat};
+    use crate::api::PinInput;
+
+    fn parse(content: &str) -> Result<Manifest, Vec<(Option<u64>, String)>> {
+        Manifest::parse(content.as_bytes(), ManifestFormat::Csv).map_err(|errors| {
+            errors
+                .into_iter()
+                .map(|error| (error.line, error.message))
+                .collect()
+        })
+    }
+
+    fn pin(code: &str) -> PinInput {
+        PinInput {
+            code: code.to_string(),
+            location: None,
+            name: None,
+            protein_acronym: None,
+            smiles: None,
+            comments: None,
+        }
+    }
+
+    #[test]
+    fn accepts_any_heading_of_each_column() {
+        let manifest = parse(
+            "Dewar Barcode, PUCK ,Location,Pin Barcode,Protein Acronym,Sample Name,Compound SMILES\n\
+             DLS-1,CPS-1,3,P-1,LYS,Crystal 1,CCO\n",
+        )
+        .unwrap();
+        let dewars = manifest.into_dewars();
+        assert_eq!(dewars.len(), 1);
+        assert_eq!(dewars[0].code, "DLS-1");
+        assert_eq!(dewars[0].pucks[0].code, "CPS-1");
+        let pin = &dewars[0].pucks[0].pins[0];
+        assert_eq!(pin.code, "P-1");
+        assert_eq!(pin.location.as_deref(), Some("3"));
+        assert_eq!(pin.protein_acronym.as_deref(), Some("LYS"));
+        assert_eq!(pin.name.as_deref(), Some("Crystal 1"));
+        assert_eq!(pin.smiles.as_deref(), Some("CCO"));
+    }
+
+    #[test]
+    fn reports_each_missing_column() {
+        assert_eq!(
+            parse("dewar,position,protein\nDLS-1,1,LYS\n").unwrap_err(),
+            vec![
+                (
+                    Some(1),
+                    "Missing a column headed 'puck barcode'".to_string()
+                ),
+                (Some(1), "Missing a column headed 'pin barcode'".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_missing_cells() {
+        assert_eq!(
+            parse("dewar,puck,pin\nDLS-1,,P-1\n,CPS-1,\n").unwrap_err(),
+            vec![
+                (Some(2), "Missing puck barcode".to_string()),
+                (Some(3), "Missing dewar barcode".to_string()),
+                (Some(3), "Missing pin barcode".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_duplicate_pins() {
+        assert_eq!(
+            parse("dewar,puck,pin\nDLS-1,CPS-1,P-1\nDLS-1,CPS-2,P-2\nDLS-1,CPS-2,P-1\n")
+                .unwrap_err(),
+            vec![(Some(4), "Pin P-1 already appears on line 2".to_string())]
+        );
+    }
+
+    #[test]
+    fn reports_duplicate_positions() {
+        assert_eq!(
+            parse(
+                "dewar,puck,position,pin\n\
+                 DLS-1,CPS-1,1,P-1\n\
+                 DLS-1,CPS-2,1,P-2\n\
+                 DLS-1,CPS-1,1,P-3\n\
+                 DLS-1,CPS-1,0,P-4\n"
+            )
+            .unwrap_err(),
+            vec![
+                (
+                    Some(4),
+                    "Position 1 of puck CPS-1 is already occupied".to_string()
+                ),
+                (
+                    Some(5),
+                    "Position '0' is not a positive integer".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_pucks_listed_in_two_dewars() {
+        assert_eq!(
+            parse("dewar,puck,pin\nDLS-1,CPS-1,P-1\nDLS-2,CPS-1,P-2\n").unwrap_err(),
+            vec![(
+                Some(3),
+                "Puck CPS-1 is already in dewar DLS-1, not DLS-2".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn skips_blank_rows() {
+        let manifest = parse("dewar,puck,pin\n\nDLS-1,CPS-1,P-1\n , ,\n").unwrap();
+        assert_eq!(manifest.pin_count(), 1);
+    }
+
+    #[test]
+    fn groups_pins_in_order_of_first_appearance() {
+        let dewars = group_pins([
+            ("DLS-2".to_string(), "CPS-3".to_string(), pin("P-1")),
+            ("DLS-1".to_string(), "CPS-1".to_string(), pin("P-2")),
+            ("DLS-2".to_string(), "CPS-4".to_string(), pin("P-3")),
+            ("DLS-1".to_string(), "CPS-2".to_string(), pin("P-4")),
+            ("DLS-2".to_string(), "CPS-3".to_string(), pin("P-5")),
+        ]);
+        let layout = dewars
+            .iter()
+            .map(|dewar| {
+                (
+                    dewar.code.as_str(),
+                    dewar
+                        .pucks
+                        .iter()
+                        .map(|puck| {
+                            (
+                                puck.code.as_str(),
+                                puck.pins
+                                    .iter()
+                                    .map(|pin| pin.code.as_str())
+                                    .collect::<Vec<_>>(),
+                            )
+                        })
+                        .collect::<Vec<_>>(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            layout,
+            vec![
+                (
+                    "DLS-2",
+                    vec![("CPS-3", vec!["P-1", "P-5"]), ("CPS-4", vec!["P-3"])]
+                ),
+                (
+                    "DLS-1",
+                    vec![("CPS-1", vec!["P-2"]), ("CPS-2", vec!["P-4"])]
+                ),
+            ]
+        );
+    }
+}
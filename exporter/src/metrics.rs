@@ -0,0 +1,243 @@
+use crate::api::NodeType;
+use async_graphql::{
+    extensions::{
+        Extension, ExtensionContext, ExtensionFactory, NextExecute, NextParseQuery, NextSubscribe,
+        NextValidation,
+    },
+    futures_util::{stream::BoxStream, StreamExt},
+    parser::types::ExecutableDocument,
+    Response, ServerError, ServerResult, ValidationResult, Value, Variables,
+};
+use axum::{
+    http::{header::CONTENT_TYPE, StatusCode},
+    response::{IntoResponse, Response as HttpResponse},
+    Extension as HttpExtension,
+};
+use lazy_static::lazy_static;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge,
+    register_int_gauge_vec, Encoder, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, TextEncoder,
+};
+use sea_orm::{metric::Info, DatabaseConnection};
+use std::{collections::HashSet, sync::Arc, time::Instant};
+
+/// The label recorded for operations whose names are not listed in `metrics.operation_names`
+const UNKNOWN_OPERATION: &str = "unknown";
+
+lazy_static! {
+    static ref GRAPHQL_OPERATIONS: IntCounterVec = register_int_counter_vec!(
+        "exporter_graphql_operations_total",
+        "The number of GraphQL operations executed, by operation name",
+        &["operation"]
+    )
+    .unwrap();
+    static ref GRAPHQL_OPERATION_DURATION: HistogramVec = register_histogram_vec!(
+        "exporter_graphql_operation_duration_seconds",
+        "The time taken to execute each GraphQL operation, by operation name",
+        &["operation"]
+    )
+    .unwrap();
+    static ref GRAPHQL_ERRORS: IntCounterVec = register_int_counter_vec!(
+        "exporter_graphql_errors_total",
+        "The number of errors returned from GraphQL requests, by the stage or code of the error",
+        &["kind"]
+    )
+    .unwrap();
+    static ref ACTIVE_SUBSCRIPTIONS: IntGauge = register_int_gauge!(
+        "exporter_graphql_active_subscriptions",
+        "The number of GraphQL subscriptions currently streaming events"
+    )
+    .unwrap();
+    static ref BROKER_MISSED_EVENTS: IntCounter = register_int_counter!(
+        "exporter_broker_missed_events_total",
+        "The number of events missed by subscribers which lagged behind the event broker"
+    )
+    .unwrap();
+    static ref DATABASE_CONNECTIONS: IntGaugeVec = register_int_gauge_vec!(
+        "exporter_database_connections",
+        "The number of connections to ISPyB held by the pool, by whether they are in use",
+        &["state"]
+    )
+    .unwrap();
+    static ref DATABASE_MAX_CONNECTIONS: IntGauge = register_int_gauge!(
+        "exporter_database_max_connections",
+        "The largest number of connections the pool may hold"
+    )
+    .unwrap();
+    static ref DATABASE_QUERY_DURATION: HistogramVec = register_histogram_vec!(
+        "exporter_database_query_duration_seconds",
+        "The time taken to execute each database statement, by whether it succeeded",
+        &["outcome"]
+    )
+    .unwrap();
+    static ref CREATED_ENTITIES: IntCounterVec = register_int_counter_vec!(
+        "exporter_created_total",
+        "The number of shipments, dewars, pucks and pins created by the exporter",
+        &["type"]
+    )
+    .unwrap();
+}
+
+/// Records the duration of a database statement, as reported by the connection
+pub fn record_query(info: &Info<'_>) {
+    DATABASE_QUERY_DURATION
+        .with_label_values(&[if info.failed { "failed" } else { "succeeded" }])
+        .observe(info.elapsed.as_secs_f64());
+}
+
+/// Records the events a subscriber missed after lagging behind the event broker
+pub fn record_missed_events(missed: u64) {
+    BROKER_MISSED_EVENTS.inc_by(missed);
+}
+
+/// Records the creation of a shipment or one of its contents
+pub fn record_created(node_type: NodeType) {
+    CREATED_ENTITIES
+        .with_label_values(&[&node_type.to_string()])
+        .inc();
+}
+
+/// Counts a subscription as active until it is dropped
+struct ActiveSubscription;
+
+impl ActiveSubscription {
+    fn new() -> Self {
+        ACTIVE_SUBSCRIPTIONS.inc();
+        Self
+    }
+}
+
+impl Drop for ActiveSubscription {
+    fn drop(&mut self) {
+        ACTIVE_SUBSCRIPTIONS.dec();
+    }
+}
+
+fn record_errors(errors: &[ServerError], default_kind: &str) {
+    for error in errors {
+        let kind = match error
+            .extensions
+            .as_ref()
+            .and_then(|extensions| extensions.get("code"))
+        {
+            Some(Value::String(code)) => code.as_str(),
+            _ => default_kind,
+        };
+        GRAPHQL_ERRORS.with_label_values(&[kind]).inc();
+    }
+}
+
+/// An extension recording the count, duration and errors of GraphQL operations, alongside the
+/// number of active subscriptions. Operation names are chosen by clients, so only the listed names
+/// are recorded as labels, with all others recorded as `unknown`.
+pub struct GraphQLMetrics {
+    operation_names: Arc<HashSet<String>>,
+}
+
+impl GraphQLMetrics {
+    pub fn new(operation_names: impl IntoIterator<Item = String>) -> Self {
+        Self {
+            operation_names: Arc::new(operation_names.into_iter().collect()),
+        }
+    }
+}
+
+impl ExtensionFactory for GraphQLMetrics {
+    fn create(&self) -> Arc<dyn Extension> {
+        Arc::new(GraphQLMetricsExtension {
+            operation_names: self.operation_names.clone(),
+        })
+    }
+}
+
+struct GraphQLMetricsExtension {
+    operation_names: Arc<HashSet<String>>,
+}
+
+#[async_trait::async_trait]
+impl Extension for GraphQLMetricsExtension {
+    fn subscribe<'s>(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        stream: BoxStream<'s, Response>,
+        next: NextSubscribe<'_>,
+    ) -> BoxStream<'s, Response> {
+        let active_subscription = ActiveSubscription::new();
+        next.run(ctx, stream)
+            .map(move |response| {
+                let _ = &active_subscription;
+                response
+            })
+            .boxed()
+    }
+
+    async fn parse_query(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        query: &str,
+        variables: &Variables,
+        next: NextParseQuery<'_>,
+    ) -> ServerResult<ExecutableDocument> {
+        let document = next.run(ctx, query, variables).await;
+        if let Err(err) = &document {
+            record_errors(std::slice::from_ref(err), "PARSE");
+        }
+        document
+    }
+
+    async fn validation(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        next: NextValidation<'_>,
+    ) -> Result<ValidationResult, Vec<ServerError>> {
+        let result = next.run(ctx).await;
+        if let Err(errors) = &result {
+            record_errors(errors, "VALIDATION");
+        }
+        result
+    }
+
+    async fn execute(
+        &self,
+        ctx: &ExtensionContext<'_>,
+        operation_name: Option<&str>,
+        next: NextExecute<'_>,
+    ) -> Response {
+        let operation = match operation_name {
+            Some(operation_name) if self.operation_names.contains(operation_name) => operation_name,
+            Some(_) => UNKNOWN_OPERATION,
+            None => "anonymous",
+        };
+        let start = Instant::now();
+        let response = next.run(ctx, operation_name).await;
+        GRAPHQL_OPERATIONS.with_label_values(&[operation]).inc();
+        GRAPHQL_OPERATION_DURATION
+            .with_label_values(&[operation])
+            .observe(start.elapsed().as_secs_f64());
+        record_errors(&response.errors, "RESOLVER");
+        response
+    }
+}
+
+/// Serves every metric in the Prometheus text format, sampling the database pool when scraped
+pub async fn metrics_handler(
+    HttpExtension(database): HttpExtension<DatabaseConnection>,
+) -> HttpResponse {
+    if let DatabaseConnection::SqlxMySqlPoolConnection(_) = database {
+        let pool = database.get_mysql_connection_pool();
+        let idle = pool.num_idle() as i64;
+        DATABASE_CONNECTIONS.with_label_values(&["idle"]).set(idle);
+        DATABASE_CONNECTIONS
+            .with_label_values(&["in_use"])
+            .set(pool.size() as i64 - idle);
+        DATABASE_MAX_CONNECTIONS.set(pool.options().get_max_connections() as i64);
+    }
+
+    let encoder = TextEncoder::new();
+    let mut body = Vec::new();
+    match encoder.encode(&prometheus::gather(), &mut body) {
+        Ok(()) => ([(CONTENT_TYPE, encoder.format_type().to_string())], body).into_response(),
+        Err(err) => (StatusCode::INTERNAL_SERVER_ERROR, err.to_string()).into_response(),
+    }
+}
@@ -0,0 +1,528 @@
+use crate::{
+    api::{MutationType, NodeId, NodeType, ShipmentEvent},
+    broker::SharedEventBroker,
+    tables::{is_duplicate_key, lease},
+};
+use chrono::NaiveDateTime;
+use models::{bl_sample, container, dewar, shipping};
+use sea_orm::{
+    sea_query::Expr, ColumnTrait, Condition, DatabaseConnection, DbErr, EntityTrait, QueryFilter,
+    Set, TransactionTrait,
+};
+use std::{collections::HashMap, time::Duration};
+
+/// The name of the lease held by the replica which polls ISPyB
+const POLLER_LEASE: &str = "poller";
+
+/// A tracked row, reduced to what is needed to locate it within the shipment tree and to detect
+/// changes to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Row {
+    /// The proposal of a shipment, the shipment of a dewar, the dewar of a puck or the puck of a pin
+    parent_id: Option<u32>,
+    /// The creation date of a shipment, which is not tracked for other rows
+    creation_date: Option<NaiveDateTime>,
+    /// The debug representation of the whole row, which differs whenever any column is updated
+    contents: String,
+}
+
+impl Row {
+    fn new(
+        parent_id: Option<u32>,
+        creation_date: Option<NaiveDateTime>,
+        model: &impl std::fmt::Debug,
+    ) -> Self {
+        Self {
+            parent_id,
+            creation_date,
+            contents: format!("{:?}", model),
+        }
+    }
+}
+
+/// The state of the tracked `Shipping`, `Dewar`, `Container` and `BLSample` rows as of a single
+/// poll
+#[derive(Debug, Default)]
+struct Snapshot {
+    shipments: HashMap<u32, Row>,
+    dewars: HashMap<u32, Row>,
+    pucks: HashMap<u32, Row>,
+    pins: HashMap<u32, Row>,
+}
+
+impl Snapshot {
+    /// Reads all shipments created after `cutoff`, along with their dewars, pucks and pins
+    async fn fetch(database: &DatabaseConnection, cutoff: NaiveDateTime) -> Result<Self, DbErr> {
+        let shipments = shipping::Entity::find()
+            .filter(shipping::Column::CreationDate.gte(cutoff))
+            .all(database)
+            .await?
+            .into_iter()
+            .map(|shipment| {
+                let row = Row::new(
+                    Some(shipment.proposal_id),
+                    shipment.creation_date,
+                    &shipment,
+                );
+                (shipment.shipping_id, row)
+            })
+            .collect::<HashMap<_, _>>();
+        let dewars = dewar::Entity::find()
+            .filter(dewar::Column::ShippingId.is_in(shipments.keys().copied()))
+            .all(database)
+            .await?
+            .into_iter()
+            .map(|dewar| (dewar.dewar_id, Row::new(dewar.shipping_id, None, &dewar)))
+            .collect::<HashMap<_, _>>();
+        let pucks = container::Entity::find()
+            .filter(container::Column::DewarId.is_in(dewars.keys().copied()))
+            .all(database)
+            .await?
+            .into_iter()
+            .map(|puck| (puck.container_id, Row::new(puck.dewar_id, None, &puck)))
+            .collect::<HashMap<_, _>>();
+        let pins = bl_sample::Entity::find()
+            .filter(bl_sample::Column::ContainerId.is_in(pucks.keys().copied()))
+            .all(database)
+            .await?
+            .into_iter()
+            .map(|pin| (pin.bl_sample_id, Row::new(pin.container_id, None, &pin)))
+            .collect::<HashMap<_, _>>();
+        Ok(Self {
+            shipments,
+            dewars,
+            pucks,
+            pins,
+        })
+    }
+
+    fn shipment_of_dewar(&self, dewar_id: u32) -> Option<u32> {
+        self.dewars.get(&dewar_id)?.parent_id
+    }
+
+    fn shipment_of_puck(&self, puck_id: u32) -> Option<u32> {
+        self.shipment_of_dewar(self.pucks.get(&puck_id)?.parent_id?)
+    }
+
+    fn shipment_of_pin(&self, pin_id: u32) -> Option<u32> {
+        self.shipment_of_puck(self.pins.get(&pin_id)?.parent_id?)
+    }
+
+    /// Locates an entity within the shipment tree, returning its shipment and proposal ids
+    fn locate(&self, entity: NodeId) -> Option<(u32, u32)> {
+        let shipment_id = match entity.node_type {
+            NodeType::Shipment => Some(entity.id),
+            NodeType::Dewar => self.shipment_of_dewar(entity.id),
+            NodeType::Puck => self.shipment_of_puck(entity.id),
+            NodeType::Pin => self.shipment_of_pin(entity.id),
+            _ => None,
+        }?;
+        Some((shipment_id, self.shipments.get(&shipment_id)?.parent_id?))
+    }
+}
+
+/// Compares two sets of rows, returning the ids of those which were created, updated or deleted
+fn diff(previous: &HashMap<u32, Row>, current: &HashMap<u32, Row>) -> Vec<(MutationType, u32)> {
+    let mut changes = current
+        .iter()
+        .filter_map(|(id, row)| match previous.get(id) {
+            None => Some((MutationType::Created, *id)),
+            Some(previous_row) if previous_row != row => Some((MutationType::Updated, *id)),
+            Some(_) => None,
+        })
+        .chain(
+            previous
+                .keys()
+                .filter(|id| !current.contains_key(id))
+                .map(|id| (MutationType::Deleted, *id)),
+        )
+        .collect::<Vec<_>>();
+    changes.sort_by_key(|(_, id)| *id);
+    changes
+}
+
+/// Lists the changes between two snapshots, ignoring rows which have simply aged out of the window
+fn changes(previous: &Snapshot, current: &Snapshot, cutoff: NaiveDateTime) -> Vec<ShipmentChange> {
+    let shipment_changes = diff(&previous.shipments, &current.shipments)
+        .into_iter()
+        .filter(|(mutation_type, id)| {
+            *mutation_type != MutationType::Deleted
+                || previous.shipments[id]
+                    .creation_date
+                    .map_or(false, |creation_date| creation_date >= cutoff)
+        })
+        .map(|(mutation_type, id)| (mutation_type, NodeId::new(NodeType::Shipment, id)));
+    let dewar_changes = diff(&previous.dewars, &current.dewars)
+        .into_iter()
+        .filter(|(mutation_type, id)| {
+            *mutation_type != MutationType::Deleted
+                || previous
+                    .shipment_of_dewar(*id)
+                    .map_or(false, |shipment_id| {
+                        current.shipments.contains_key(&shipment_id)
+                    })
+        })
+        .map(|(mutation_type, id)| (mutation_type, NodeId::new(NodeType::Dewar, id)));
+    let puck_changes = diff(&previous.pucks, &current.pucks)
+        .into_iter()
+        .filter(|(mutation_type, id)| {
+            *mutation_type != MutationType::Deleted
+                || previous.pucks[id]
+                    .parent_id
+                    .map_or(false, |dewar_id| current.dewars.contains_key(&dewar_id))
+        })
+        .map(|(mutation_type, id)| (mutation_type, NodeId::new(NodeType::Puck, id)));
+    let pin_changes = diff(&previous.pins, &current.pins)
+        .into_iter()
+        .filter(|(mutation_type, id)| {
+            *mutation_type != MutationType::Deleted
+                || previous.pins[id]
+                    .parent_id
+                    .map_or(false, |puck_id| current.pucks.contains_key(&puck_id))
+        })
+        .map(|(mutation_type, id)| (mutation_type, NodeId::new(NodeType::Pin, id)));
+
+    shipment_changes
+        .chain(dewar_changes)
+        .chain(puck_changes)
+        .chain(pin_changes)
+        .filter_map(|(mutation_type, entity)| {
+            let snapshot = match mutation_type {
+                MutationType::Deleted => previous,
+                _ => current,
+            };
+            let (shipment_id, proposal_id) = snapshot.locate(entity)?;
+            Some(ShipmentChange {
+                mutation_type,
+                proposal_id,
+                shipment_id,
+                entity,
+            })
+        })
+        .collect()
+}
+
+#[derive(Debug, Clone, Copy)]
+struct ShipmentChange {
+    mutation_type: MutationType,
+    proposal_id: u32,
+    shipment_id: u32,
+    entity: NodeId,
+}
+
+/// Records an event for each change within a single transaction, returning them for publishing.
+/// Creations which already have an event in the event log, such as those made through the
+/// exporter, are skipped.
+async fn record_changes(
+    changes: &[ShipmentChange],
+    database: &DatabaseConnection,
+) -> Result<Vec<ShipmentEvent>, DbErr> {
+    if changes.is_empty() {
+        return Ok(Vec::new());
+    }
+    let transaction = database.begin().await?;
+    ShipmentEvent::lock_log(&transaction).await?;
+    let recorded_creations = ShipmentEvent::recorded_creations(
+        changes
+            .iter()
+            .filter(|change| change.mutation_type == MutationType::Created)
+            .map(|change| change.entity),
+        &transaction,
+    )
+    .await?;
+    let mut events = Vec::with_capacity(changes.len());
+    for change in changes.iter().filter(|change| {
+        change.mutation_type != MutationType::Created
+            || !recorded_creations.contains(&change.entity)
+    }) {
+        events.push(
+            ShipmentEvent::record(
+                change.mutation_type,
+                change.proposal_id,
+                change.shipment_id,
+                change.entity,
+                &transaction,
+            )
+            .await?,
+        );
+    }
+    transaction.commit().await?;
+    Ok(events)
+}
+
+/// Takes the poller lease, or renews it if already held by this replica, until `duration` from
+/// now, returning whether it is held
+async fn acquire_lease(
+    holder: &str,
+    duration: chrono::Duration,
+    database: &DatabaseConnection,
+) -> Result<bool, DbErr> {
+    let now = chrono::Utc::now();
+    let insert = lease::Entity::insert(lease::ActiveModel {
+        name: Set(POLLER_LEASE.to_string()),
+        holder: Set(holder.to_string()),
+        expires_at: Set(now + duration),
+    })
+    .exec(database)
+    .await;
+    match insert {
+        Ok(_) => return Ok(true),
+        Err(err) if !is_duplicate_key(&err) => return Err(err),
+        Err(_) => {}
+    }
+    let update = lease::Entity::update_many()
+        .col_expr(lease::Column::Holder, Expr::value(holder))
+        .col_expr(lease::Column::ExpiresAt, Expr::value(now + duration))
+        .filter(lease::Column::Name.eq(POLLER_LEASE))
+        .filter(
+            Condition::any()
+                .add(lease::Column::Holder.eq(holder))
+                .add(lease::Column::ExpiresAt.lt(now)),
+        )
+        .exec(database)
+        .await?;
+    Ok(update.rows_affected == 1)
+}
+
+/// Periodically polls ISPyB for changes to recently created shipments, their dewars, their pucks
+/// and their pins, publishing an event for each. This detects changes made outside of the
+/// exporter, such as a dewar being marked as received by SynchWeb. Entities whose creation was
+/// already recorded in the event log are not announced again. Every replica may run the poller,
+/// but only the one holding the poller lease polls, with another taking over should it expire.
+/// Changes made while the lease changes hands are missed, as a replica compares only against
+/// snapshots taken while it held the lease.
+pub async fn poll_for_changes(
+    database: DatabaseConnection,
+    event_broker: SharedEventBroker<ShipmentEvent>,
+    interval: Duration,
+    lookback: Duration,
+) {
+    let lookback = chrono::Duration::from_std(lookback).expect("Poll lookback out of range");
+    let lease_duration =
+        chrono::Duration::from_std(interval * 2).expect("Poll interval out of range");
+    let holder = uuid::Uuid::new_v4().to_string();
+    let mut previous = None;
+    let mut ticker = tokio::time::interval(interval);
+    loop {
+        ticker.tick().await;
+        match acquire_lease(&holder, lease_duration, &database).await {
+            Ok(true) => {}
+            Ok(false) => {
+                previous = None;
+                continue;
+            }
+            Err(err) => {
+                tracing::warn!("Could not take the poller lease: {}", err);
+                previous = None;
+                continue;
+            }
+        }
+        let cutoff = chrono::Local::now().naive_local() - lookback;
+        let current = match Snapshot::fetch(&database, cutoff).await {
+            Ok(snapshot) => snapshot,
+            Err(err) => {
+                tracing::warn!("Could not poll ISPyB for changes: {}", err);
+                continue;
+            }
+        };
+
+        if let Some(previous) = &previous {
+            let changes = changes(previous, &current, cutoff);
+            match record_changes(&changes, &database).await {
+                Ok(events) => {
+                    for event in events {
+                        event_broker.publish(event);
+                    }
+                }
+                Err(err) => {
+                    // The changes are found again by the next poll, which compares against the
+                    // same previous snapshot
+                    tracing::error!("Could not record {} changes: {}", changes.len(), err);
+                    continue;
+                }
+            }
+        }
+        previous = Some(current);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{changes, diff, Row, Snapshot};
+    use crate::api::{MutationType, NodeId, NodeType};
+    use chrono::{NaiveDate, NaiveDateTime};
+    use std::collections::HashMap;
+
+    fn day(day: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(2023, 6, day)
+            .unwrap()
+            .and_hms_opt(0, 0, 0)
+            .unwrap()
+    }
+
+    fn row(parent_id: u32, contents: &str) -> Row {
+        Row {
+            parent_id: Some(parent_id),
+            creation_date: None,
+            contents: contents.to_string(),
+        }
+    }
+
+    fn shipment(proposal_id: u32, created: u32) -> Row {
+        Row {
+            creation_date: Some(day(created)),
+            ..row(proposal_id, "shipment")
+        }
+    }
+
+    /// A recent shipment of proposal 7 containing one puck of two pins, and an older shipment of
+    /// proposal 8 containing an empty dewar
+    fn snapshot() -> Snapshot {
+        Snapshot {
+            shipments: HashMap::from([(1, shipment(7, 20)), (2, shipment(8, 1))]),
+            dewars: HashMap::from([(10, row(1, "dewar")), (20, row(2, "dewar"))]),
+            pucks: HashMap::from([(100, row(10, "puck"))]),
+            pins: HashMap::from([(1000, row(100, "pin")), (1001, row(100, "pin"))]),
+        }
+    }
+
+    /// Lists the changes between snapshots as tuples of their mutation type, entity, shipment id
+    /// and proposal id, with a cutoff of the tenth day
+    fn changes_between(
+        previous: &Snapshot,
+        current: &Snapshot,
+    ) -> Vec<(MutationType, NodeId, u32, u32)> {
+        changes(previous, current, day(10))
+            .into_iter()
+            .map(|change| {
+                (
+                    change.mutation_type,
+                    change.entity,
+                    change.shipment_id,
+                    change.proposal_id,
+                )
+            })
+            .collect()
+    }
+
+    #[test]
+    fn classifies_rows_by_id() {
+        let previous = HashMap::from([(1, row(0, "a")), (2, row(0, "b")), (3, row(0, "c"))]);
+        let current = HashMap::from([(2, row(0, "b")), (3, row(0, "C")), (4, row(0, "d"))]);
+        assert_eq!(
+            diff(&previous, &current),
+            vec![
+                (MutationType::Deleted, 1),
+                (MutationType::Updated, 3),
+                (MutationType::Created, 4),
+            ]
+        );
+    }
+
+    #[test]
+    fn finds_no_changes_between_identical_snapshots() {
+        assert_eq!(changes_between(&snapshot(), &snapshot()), vec![]);
+    }
+
+    #[test]
+    fn locates_created_and_updated_rows() {
+        let mut current = snapshot();
+        current.dewars.insert(10, row(1, "received dewar"));
+        current.pins.insert(1002, row(100, "pin"));
+        assert_eq!(
+            changes_between(&snapshot(), &current),
+            vec![
+                (
+                    MutationType::Updated,
+                    NodeId::new(NodeType::Dewar, 10),
+                    1,
+                    7
+                ),
+                (
+                    MutationType::Created,
+                    NodeId::new(NodeType::Pin, 1002),
+                    1,
+                    7
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_pins_moved_between_pucks() {
+        let mut current = snapshot();
+        current.pucks.insert(101, row(10, "puck"));
+        current.pins.insert(1000, row(101, "pin"));
+        assert_eq!(
+            changes_between(&snapshot(), &current),
+            vec![
+                (
+                    MutationType::Created,
+                    NodeId::new(NodeType::Puck, 101),
+                    1,
+                    7
+                ),
+                (
+                    MutationType::Updated,
+                    NodeId::new(NodeType::Pin, 1000),
+                    1,
+                    7
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn ignores_shipments_which_aged_out_of_the_window() {
+        let mut current = snapshot();
+        current.shipments.remove(&2);
+        current.dewars.remove(&20);
+        assert_eq!(changes_between(&snapshot(), &current), vec![]);
+    }
+
+    #[test]
+    fn reports_deleted_shipments_without_their_contents() {
+        let current = Snapshot {
+            shipments: HashMap::from([(2, shipment(8, 1))]),
+            dewars: HashMap::from([(20, row(2, "dewar"))]),
+            ..Snapshot::default()
+        };
+        assert_eq!(
+            changes_between(&snapshot(), &current),
+            vec![(
+                MutationType::Deleted,
+                NodeId::new(NodeType::Shipment, 1),
+                1,
+                7
+            )]
+        );
+    }
+
+    #[test]
+    fn reports_deleted_pins_of_remaining_pucks() {
+        let mut current = snapshot();
+        current.pins.remove(&1001);
+        assert_eq!(
+            changes_between(&snapshot(), &current),
+            vec![(
+                MutationType::Deleted,
+                NodeId::new(NodeType::Pin, 1001),
+                1,
+                7
+            )]
+        );
+    }
+
+    #[test]
+    fn omits_rows_outside_of_any_shipment() {
+        let mut current = snapshot();
+        current.dewars.insert(
+            30,
+            Row {
+                parent_id: None,
+                ..row(0, "dewar")
+            },
+        );
+        assert_eq!(changes_between(&snapshot(), &current), vec![]);
+    }
+}
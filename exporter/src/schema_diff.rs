@@ -0,0 +1,479 @@
+use async_graphql::parser::{
+    parse_schema,
+    types::{
+        BaseType, FieldDefinition, InputValueDefinition, ServiceDocument, Type, TypeDefinition,
+        TypeKind, TypeSystemDefinition,
+    },
+    Positioned,
+};
+use std::{collections::BTreeMap, fmt::Display};
+
+/// A difference between two versions of a schema, classified by whether existing clients of the
+/// old schema may break when served the new one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SchemaChange {
+    Breaking(String),
+    NonBreaking(String),
+}
+
+impl SchemaChange {
+    pub fn is_breaking(&self) -> bool {
+        matches!(self, Self::Breaking(_))
+    }
+}
+
+impl Display for SchemaChange {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Breaking(description) => write!(f, "BREAKING: {}", description),
+            Self::NonBreaking(description) => write!(f, "non-breaking: {}", description),
+        }
+    }
+}
+
+/// The exit status of a schema check: success if the schemas are equivalent, however their text
+/// differs, 1 if every change is non-breaking and 2 if any change is breaking
+pub fn exit_status(changes: &[SchemaChange]) -> i32 {
+    if changes.iter().any(SchemaChange::is_breaking) {
+        2
+    } else if changes.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+/// Compares two schemas in SDL, listing every change made to the types of the old schema
+pub fn diff_schemas(old: &str, new: &str) -> async_graphql::parser::Result<Vec<SchemaChange>> {
+    let old_types = types_by_name(parse_schema(old)?);
+    let new_types = types_by_name(parse_schema(new)?);
+    let mut changes = Vec::new();
+    for (name, old_type) in old_types.iter() {
+        match new_types.get(name) {
+            Some(new_type) => diff_types(name, old_type, new_type, &mut changes),
+            None => changes.push(SchemaChange::Breaking(format!("Type {} was removed", name))),
+        }
+    }
+    for name in new_types.keys() {
+        if !old_types.contains_key(name) {
+            changes.push(SchemaChange::NonBreaking(format!(
+                "Type {} was added",
+                name
+            )));
+        }
+    }
+    Ok(changes)
+}
+
+fn types_by_name(document: ServiceDocument) -> BTreeMap<String, TypeDefinition> {
+    document
+        .definitions
+        .into_iter()
+        .filter_map(|definition| match definition {
+            TypeSystemDefinition::Type(definition) => {
+                Some((definition.node.name.node.to_string(), definition.node))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn kind_name(kind: &TypeKind) -> &'static str {
+    match kind {
+        TypeKind::Scalar => "scalar",
+        TypeKind::Object(_) => "object",
+        TypeKind::Interface(_) => "interface",
+        TypeKind::Union(_) => "union",
+        TypeKind::Enum(_) => "enum",
+        TypeKind::InputObject(_) => "input object",
+    }
+}
+
+fn names<'a, T: 'a>(
+    items: impl IntoIterator<Item = &'a Positioned<T>>,
+    name: impl Fn(&T) -> String,
+) -> Vec<String> {
+    items.into_iter().map(|item| name(&item.node)).collect()
+}
+
+/// Records the removal of members, which is breaking, and the addition of members, which is not
+fn diff_members(
+    description: &str,
+    old: Vec<String>,
+    new: Vec<String>,
+    changes: &mut Vec<SchemaChange>,
+) {
+    for member in old.iter().filter(|member| !new.contains(member)) {
+        changes.push(SchemaChange::Breaking(format!(
+            "{} {} was removed",
+            description, member
+        )));
+    }
+    for member in new.iter().filter(|member| !old.contains(member)) {
+        changes.push(SchemaChange::NonBreaking(format!(
+            "{} {} was added",
+            description, member
+        )));
+    }
+}
+
+fn diff_types(
+    name: &str,
+    old: &TypeDefinition,
+    new: &TypeDefinition,
+    changes: &mut Vec<SchemaChange>,
+) {
+    match (&old.kind, &new.kind) {
+        (TypeKind::Scalar, TypeKind::Scalar) => {}
+        (TypeKind::Object(old), TypeKind::Object(new)) => {
+            diff_members(
+                &format!("Interface implemented by {}", name),
+                names(&old.implements, |name| name.to_string()),
+                names(&new.implements, |name| name.to_string()),
+                changes,
+            );
+            diff_fields(name, &old.fields, &new.fields, changes);
+        }
+        (TypeKind::Interface(old), TypeKind::Interface(new)) => {
+            diff_members(
+                &format!("Interface implemented by {}", name),
+                names(&old.implements, |name| name.to_string()),
+                names(&new.implements, |name| name.to_string()),
+                changes,
+            );
+            diff_fields(name, &old.fields, &new.fields, changes);
+        }
+        (TypeKind::Union(old), TypeKind::Union(new)) => diff_members(
+            &format!("Member of union {}", name),
+            names(&old.members, |name| name.to_string()),
+            names(&new.members, |name| name.to_string()),
+            changes,
+        ),
+        (TypeKind::Enum(old), TypeKind::Enum(new)) => diff_members(
+            &format!("Value of enum {}", name),
+            names(&old.values, |value| value.value.node.to_string()),
+            names(&new.values, |value| value.value.node.to_string()),
+            changes,
+        ),
+        (TypeKind::InputObject(old), TypeKind::InputObject(new)) => diff_input_values(
+            &format!("Field {}", name),
+            &old.fields,
+            &new.fields,
+            changes,
+        ),
+        (old_kind, new_kind) => changes.push(SchemaChange::Breaking(format!(
+            "Type {} changed from {} to {}",
+            name,
+            kind_name(old_kind),
+            kind_name(new_kind)
+        ))),
+    }
+}
+
+/// Whether every value of the `narrow` type is also a value of the `wide` type, such as `T!` and
+/// `T` or `[T!]` and `[T]`. Clients expecting output of the wide type accept the narrow type,
+/// while clients supplying input of the narrow type are accepted by the wide type.
+fn narrows(wide: &Type, narrow: &Type) -> bool {
+    (wide.nullable || !narrow.nullable)
+        && match (&wide.base, &narrow.base) {
+            (BaseType::Named(wide), BaseType::Named(narrow)) => wide == narrow,
+            (BaseType::List(wide), BaseType::List(narrow)) => narrows(wide, narrow),
+            _ => false,
+        }
+}
+
+fn diff_fields(
+    type_name: &str,
+    old: &[Positioned<FieldDefinition>],
+    new: &[Positioned<FieldDefinition>],
+    changes: &mut Vec<SchemaChange>,
+) {
+    for old_field in old.iter().map(|field| &field.node) {
+        let field_name = format!("{}.{}", type_name, old_field.name.node);
+        match new
+            .iter()
+            .map(|field| &field.node)
+            .find(|field| field.name.node == old_field.name.node)
+        {
+            Some(new_field) => {
+                if old_field.ty.node != new_field.ty.node {
+                    let description = format!(
+                        "Field {} changed type from {} to {}",
+                        field_name, old_field.ty.node, new_field.ty.node
+                    );
+                    changes.push(if narrows(&old_field.ty.node, &new_field.ty.node) {
+                        SchemaChange::NonBreaking(description)
+                    } else {
+                        SchemaChange::Breaking(description)
+                    });
+                }
+                diff_input_values(
+                    &format!("Argument {}", field_name),
+                    &old_field.arguments,
+                    &new_field.arguments,
+                    changes,
+                );
+            }
+            None => changes.push(SchemaChange::Breaking(format!(
+                "Field {} was removed",
+                field_name
+            ))),
+        }
+    }
+    for new_field in new.iter().map(|field| &field.node) {
+        if !old
+            .iter()
+            .any(|field| field.node.name.node == new_field.name.node)
+        {
+            changes.push(SchemaChange::NonBreaking(format!(
+                "Field {}.{} was added",
+                type_name, new_field.name.node
+            )));
+        }
+    }
+}
+
+/// Compares the arguments of a field, or the fields of an input object, which clients supply. New
+/// values are only breaking if they are required, and changed types only if they are narrower.
+fn diff_input_values(
+    description: &str,
+    old: &[Positioned<InputValueDefinition>],
+    new: &[Positioned<InputValueDefinition>],
+    changes: &mut Vec<SchemaChange>,
+) {
+    for old_value in old.iter().map(|value| &value.node) {
+        match new
+            .iter()
+            .map(|value| &value.node)
+            .find(|value| value.name.node == old_value.name.node)
+        {
+            Some(new_value) if old_value.ty.node != new_value.ty.node => {
+                let description = format!(
+                    "{}.{} changed type from {} to {}",
+                    description, old_value.name.node, old_value.ty.node, new_value.ty.node
+                );
+                changes.push(if narrows(&new_value.ty.node, &old_value.ty.node) {
+                    SchemaChange::NonBreaking(description)
+                } else {
+                    SchemaChange::Breaking(description)
+                })
+            }
+            Some(_) => {}
+            None => changes.push(SchemaChange::Breaking(format!(
+                "{}.{} was removed",
+                description, old_value.name.node
+            ))),
+        }
+    }
+    for new_value in new.iter().map(|value| &value.node) {
+        if old
+            .iter()
+            .any(|value| value.node.name.node == new_value.name.node)
+        {
+            continue;
+        }
+        let description = format!("{}.{} was added", description, new_value.name.node);
+        if !new_value.ty.node.nullable && new_value.default_value.is_none() {
+            changes.push(SchemaChange::Breaking(format!(
+                "{} as a required value",
+                description
+            )));
+        } else {
+            changes.push(SchemaChange::NonBreaking(description));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{diff_schemas, exit_status, SchemaChange};
+
+    const SCHEMA: &str = "
+        type Query {
+            shipment(id: Int!): Shipment
+        }
+
+        type Shipment {
+            id: Int!
+            name: String
+            status: Status!
+            dewars: [String!]!
+        }
+
+        enum Status {
+            OPENED
+            SENT
+        }
+    ";
+
+    fn diff(new: &str) -> Vec<SchemaChange> {
+        diff_schemas(SCHEMA, new).unwrap()
+    }
+
+    #[test]
+    fn finds_no_changes_in_an_identical_schema() {
+        assert_eq!(diff(SCHEMA), vec![]);
+    }
+
+    #[test]
+    fn finds_no_changes_in_a_reformatted_schema() {
+        let reformatted = "
+            enum Status { OPENED SENT }
+            type Shipment {
+                dewars: [String!]!
+                \"The name given to the shipment\"
+                name: String
+                id: Int!
+                status: Status!
+            }
+            type Query { shipment(id: Int!): Shipment }
+        ";
+        assert_eq!(diff(reformatted), vec![]);
+    }
+
+    #[test]
+    fn fails_checks_only_for_changes() {
+        let breaking = || SchemaChange::Breaking("Type Status was removed".to_string());
+        let non_breaking = || SchemaChange::NonBreaking("Type State was added".to_string());
+        assert_eq!(exit_status(&[]), 0);
+        assert_eq!(exit_status(&[non_breaking()]), 1);
+        assert_eq!(exit_status(&[non_breaking(), breaking()]), 2);
+    }
+
+    #[test]
+    fn breaks_on_a_removed_field() {
+        assert_eq!(
+            diff(&SCHEMA.replace("name: String", "")),
+            vec![SchemaChange::Breaking(
+                "Field Shipment.name was removed".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn does_not_break_on_an_added_field() {
+        assert_eq!(
+            diff(&SCHEMA.replace("name: String", "name: String\ncomments: String")),
+            vec![SchemaChange::NonBreaking(
+                "Field Shipment.comments was added".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn breaks_on_a_changed_field_type() {
+        assert_eq!(
+            diff(&SCHEMA.replace("name: String", "name: Int")),
+            vec![SchemaChange::Breaking(
+                "Field Shipment.name changed type from String to Int".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn does_not_break_when_an_output_field_becomes_non_null() {
+        assert_eq!(
+            diff(
+                &SCHEMA
+                    .replace("name: String", "name: String!")
+                    .replace("[String!]!", "[String!]")
+                    .replace(
+                        "shipment(id: Int!): Shipment",
+                        "shipment(id: Int!): Shipment!"
+                    )
+            ),
+            vec![
+                SchemaChange::NonBreaking(
+                    "Field Query.shipment changed type from Shipment to Shipment!".to_string()
+                ),
+                SchemaChange::NonBreaking(
+                    "Field Shipment.name changed type from String to String!".to_string()
+                ),
+                SchemaChange::Breaking(
+                    "Field Shipment.dewars changed type from [String!]! to [String!]".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn breaks_only_when_an_argument_becomes_non_null() {
+        assert_eq!(
+            diff(&SCHEMA.replace("id: Int!)", "id: Int)")),
+            vec![SchemaChange::NonBreaking(
+                "Argument Query.shipment.id changed type from Int! to Int".to_string()
+            )]
+        );
+        let nullable = SCHEMA.replace("id: Int!)", "id: Int)");
+        assert_eq!(
+            diff_schemas(&nullable, SCHEMA).unwrap(),
+            vec![SchemaChange::Breaking(
+                "Argument Query.shipment.id changed type from Int to Int!".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn breaks_on_a_new_required_argument() {
+        assert_eq!(
+            diff(&SCHEMA.replace("id: Int!)", "id: Int!, code: String!)")),
+            vec![SchemaChange::Breaking(
+                "Argument Query.shipment.code was added as a required value".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn does_not_break_on_a_new_optional_argument() {
+        assert_eq!(
+            diff(&SCHEMA.replace("id: Int!)", "id: Int!, code: String)")),
+            vec![SchemaChange::NonBreaking(
+                "Argument Query.shipment.code was added".to_string()
+            )]
+        );
+        assert_eq!(
+            diff(&SCHEMA.replace("id: Int!)", "id: Int!, code: String! = \"DLS\")")),
+            vec![SchemaChange::NonBreaking(
+                "Argument Query.shipment.code was added".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn does_not_break_on_a_new_enum_value() {
+        assert_eq!(
+            diff(&SCHEMA.replace("SENT", "SENT\nRECEIVED")),
+            vec![SchemaChange::NonBreaking(
+                "Value of enum Status RECEIVED was added".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn breaks_on_a_removed_enum_value() {
+        assert_eq!(
+            diff(&SCHEMA.replace("SENT", "")),
+            vec![SchemaChange::Breaking(
+                "Value of enum Status SENT was removed".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn breaks_on_a_removed_type() {
+        assert_eq!(
+            diff(
+                &SCHEMA
+                    .replace("enum Status {", "enum State {")
+                    .replace("Status!", "State!")
+            ),
+            vec![
+                SchemaChange::Breaking(
+                    "Field Shipment.status changed type from Status! to State!".to_string()
+                ),
+                SchemaChange::Breaking("Type Status was removed".to_string()),
+                SchemaChange::NonBreaking("Type State was added".to_string()),
+            ]
+        );
+    }
+}
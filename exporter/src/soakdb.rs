@@ -0,0 +1,221 @@
+use crate::{
+    api::PinInput,
+    manifest::{Manifest, ManifestError, ManifestRow},
+};
+use rusqlite::{Connection, OpenFlags};
+use std::path::Path;
+
+/// Selects the crystals which have been mounted in a puck, along with the compound soaked into each
+const MOUNTED_CRYSTALS_QUERY: &str = "
+    SELECT
+        ID,
+        CAST(CrystalName AS TEXT),
+        CAST(Puck AS TEXT),
+        CAST(PuckPosition AS TEXT),
+        CAST(CompoundSMILES AS TEXT),
+        CAST(CompoundCode AS TEXT),
+        CAST(LibraryName AS TEXT)
+    FROM mainTable
+    WHERE CrystalName IS NOT NULL AND CrystalName != ''
+        AND Puck IS NOT NULL AND Puck != ''
+    ORDER BY ID
+";
+
+/// A crystal which has been mounted, as recorded in the `mainTable` of a SoakDB database
+#[derive(Debug, Clone)]
+pub struct MountedCrystal {
+    pub id: u64,
+    pub crystal_name: String,
+    pub puck: String,
+    pub puck_position: Option<String>,
+    pub compound_smiles: Option<String>,
+    pub compound_code: Option<String>,
+    pub library_name: Option<String>,
+}
+
+impl MountedCrystal {
+    /// Describes the soaked compound, and the library from which it came, for the sample comments
+    fn compound_description(&self) -> Option<String> {
+        match (&self.compound_code, &self.library_name) {
+            (Some(compound_code), Some(library_name)) => Some(format!(
+                "Compound {} from library {}",
+                compound_code, library_name
+            )),
+            (Some(compound_code), None) => Some(format!("Compound {}", compound_code)),
+            (None, Some(library_name)) => Some(format!("Compound from library {}", library_name)),
+            (None, None) => None,
+        }
+    }
+}
+
+/// Reads every mounted crystal from a SoakDB SQLite file, which is opened read-only
+pub fn read_mounted_crystals(
+    path: impl AsRef<Path>,
+) -> Result<Vec<MountedCrystal>, rusqlite::Error> {
+    let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    query_mounted_crystals(&connection)
+}
+
+/// Reads every mounted crystal from the `mainTable` of an open SoakDB database
+fn query_mounted_crystals(connection: &Connection) -> Result<Vec<MountedCrystal>, rusqlite::Error> {
+    let mut statement = connection.prepare(MOUNTED_CRYSTALS_QUERY)?;
+    let non_empty = |value: Option<String>| value.filter(|value| !value.trim().is_empty());
+    let crystals = statement
+        .query_map([], |row| {
+            Ok(MountedCrystal {
+                id: row.get(0)?,
+                crystal_name: row.get(1)?,
+                puck: row.get(2)?,
+                puck_position: non_empty(row.get(3)?),
+                compound_smiles: non_empty(row.get(4)?),
+                compound_code: non_empty(row.get(5)?),
+                library_name: non_empty(row.get(6)?),
+            })
+        })?
+        .collect::<Result<Vec<_>, _>>()?;
+    Ok(crystals)
+}
+
+/// Builds a manifest of a single dewar containing each puck in which crystals were mounted,
+/// validated as a spreadsheet manifest would be. SoakDB does not record pin barcodes, so each pin
+/// is identified by the name of its crystal, and each row is located by its `mainTable` ID.
+pub fn into_manifest(
+    crystals: Vec<MountedCrystal>,
+    dewar_code: String,
+    protein_acronym: Option<String>,
+) -> Result<Manifest, Vec<ManifestError>> {
+    Manifest::from_rows(crystals.into_iter().map(|crystal| {
+        let comments = crystal.compound_description();
+        ManifestRow {
+            line: crystal.id,
+            dewar: dewar_code.clone(),
+            puck: crystal.puck,
+            pin: PinInput {
+                code: crystal.crystal_name.clone(),
+                location: crystal.puck_position,
+                name: Some(crystal.crystal_name),
+                protein_acronym: protein_acronym.clone(),
+                smiles: crystal.compound_smiles,
+                comments,
+            },
+        }
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{into_manifest, query_mounted_crystals};
+    use rusqlite::Connection;
+
+    /// An in-memory SoakDB database, with the columns of `mainTable` which are read
+    fn soakdb(rows: &str) -> Connection {
+        let connection = Connection::open_in_memory().unwrap();
+        connection
+            .execute_batch(&format!(
+                "CREATE TABLE mainTable (
+                    ID INTEGER PRIMARY KEY,
+                    CrystalName TEXT,
+                    Puck TEXT,
+                    PuckPosition INTEGER,
+                    CompoundSMILES TEXT,
+                    CompoundCode TEXT,
+                    LibraryName TEXT
+                );
+                INSERT INTO mainTable VALUES {};",
+                rows
+            ))
+            .unwrap();
+        connection
+    }
+
+    #[test]
+    fn skips_crystals_which_were_not_mounted() {
+        let crystals = query_mounted_crystals(&soakdb(
+            "(1, 'x0001', 'CPS-1', 1, NULL, NULL, NULL),
+             (2, '', 'CPS-1', 2, NULL, NULL, NULL),
+             (3, NULL, 'CPS-1', 3, NULL, NULL, NULL),
+             (4, 'x0004', '', 4, NULL, NULL, NULL),
+             (5, 'x0005', NULL, 5, NULL, NULL, NULL),
+             (6, 'x0006', 'CPS-2', 1, NULL, NULL, NULL)",
+        ))
+        .unwrap();
+        assert_eq!(
+            crystals
+                .iter()
+                .map(|crystal| (crystal.id, crystal.crystal_name.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(1, "x0001"), (6, "x0006")]
+        );
+    }
+
+    #[test]
+    fn reads_blank_cells_as_absent() {
+        let crystals =
+            query_mounted_crystals(&soakdb("(1, 'x0001', 'CPS-1', NULL, '', '  ', NULL)")).unwrap();
+        assert_eq!(crystals[0].puck_position, None);
+        assert_eq!(crystals[0].compound_smiles, None);
+        assert_eq!(crystals[0].compound_code, None);
+        assert_eq!(crystals[0].library_name, None);
+    }
+
+    #[test]
+    fn describes_the_soaked_compound() {
+        let manifest = into_manifest(
+            query_mounted_crystals(&soakdb(
+                "(1, 'x0001', 'CPS-1', 1, 'CCO', 'Z1234', 'DSi-Poised'),
+                 (2, 'x0002', 'CPS-1', 2, NULL, 'Z5678', NULL),
+                 (3, 'x0003', 'CPS-1', 3, NULL, NULL, 'DSi-Poised'),
+                 (4, 'x0004', 'CPS-1', 4, NULL, NULL, NULL)",
+            ))
+            .unwrap(),
+            "DLS-1".to_string(),
+            Some("LYS".to_string()),
+        )
+        .unwrap();
+        let dewars = manifest.into_dewars();
+        assert_eq!(dewars.len(), 1);
+        assert_eq!(dewars[0].code, "DLS-1");
+        let pins = &dewars[0].pucks[0].pins;
+        assert_eq!(pins[0].code, "x0001");
+        assert_eq!(pins[0].name.as_deref(), Some("x0001"));
+        assert_eq!(pins[0].location.as_deref(), Some("1"));
+        assert_eq!(pins[0].protein_acronym.as_deref(), Some("LYS"));
+        assert_eq!(pins[0].smiles.as_deref(), Some("CCO"));
+        assert_eq!(
+            pins.iter()
+                .map(|pin| pin.comments.as_deref())
+                .collect::<Vec<_>>(),
+            vec![
+                Some("Compound Z1234 from library DSi-Poised"),
+                Some("Compound Z5678"),
+                Some("Compound from library DSi-Poised"),
+                None,
+            ]
+        );
+    }
+
+    #[test]
+    fn reports_duplicate_positions_by_id() {
+        let errors = into_manifest(
+            query_mounted_crystals(&soakdb(
+                "(4, 'x0001', 'CPS-1', 1, NULL, NULL, NULL),
+                 (7, 'x0002', 'CPS-2', 1, NULL, NULL, NULL),
+                 (9, 'x0003', 'CPS-1', 1, NULL, NULL, NULL)",
+            ))
+            .unwrap(),
+            "DLS-1".to_string(),
+            None,
+        )
+        .unwrap_err()
+        .into_iter()
+        .map(|error| (error.line, error.message))
+        .collect::<Vec<_>>();
+        assert_eq!(
+            errors,
+            vec![(
+                Some(9),
+                "Position 1 of puck CPS-1 is already occupied".to_string()
+            )]
+        );
+    }
+}
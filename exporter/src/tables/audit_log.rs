@@ -0,0 +1,24 @@
+use sea_orm::entity::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "ExporterAuditLog")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub id: u64,
+    pub timestamp: DateTimeUtc,
+    pub caller: String,
+    pub operation: String,
+    #[sea_orm(column_type = "Text")]
+    pub input: String,
+    pub shipment_id: Option<u32>,
+    #[sea_orm(column_type = "Text")]
+    pub resulting_ids: String,
+    pub succeeded: bool,
+    #[sea_orm(column_type = "Text", nullable)]
+    pub error: Option<String>,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
@@ -0,0 +1,16 @@
+use sea_orm::entity::prelude::*;
+
+/// The id of the only row, which writers of the event log lock until their transaction ends
+pub const LOCK_ID: u32 = 1;
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "ExporterEventLock")]
+pub struct Model {
+    #[sea_orm(primary_key, auto_increment = false)]
+    pub id: u32,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
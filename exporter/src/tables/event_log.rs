@@ -0,0 +1,19 @@
+use sea_orm::entity::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, Eq, DeriveEntityModel)]
+#[sea_orm(table_name = "ExporterEventLog")]
+pub struct Model {
+    #[sea_orm(primary_key)]
+    pub sequence: u64,
+    pub timestamp: DateTimeUtc,
+    pub mutation_type: String,
+    pub proposal_id: u32,
+    pub shipment_id: u32,
+    pub entity_type: String,
+    pub entity_id: u32,
+}
+
+#[derive(Copy, Clone, Debug, EnumIter, DeriveRelation)]
+pub enum Relation {}
+
+impl ActiveModelBehavior for ActiveModel {}
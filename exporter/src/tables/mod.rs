@@ -0,0 +1,110 @@
+pub mod api_key;
+pub mod audit_log;
+pub mod event_lock;
+pub mod event_log;
+pub mod idempotency_key;
+pub mod lease;
+pub mod webhook_cursor;
+pub mod webhook_delivery;
+
+use sea_orm::{
+    sea_query::Index, ConnectionTrait, DatabaseConnection, DbErr, EntityTrait, RuntimeErr, Schema,
+    Set, SqlxError, SqlxMySqlError, Statement,
+};
+
+/// The MySQL error number reported when a row would duplicate a primary or unique key
+const DUPLICATE_ENTRY_ERROR: u16 = 1062;
+
+/// The MySQL error number reported when an index of the same name already exists
+const DUPLICATE_KEY_NAME_ERROR: u16 = 1061;
+
+/// The name of the index ensuring each event is queued for delivery to each URL only once
+const WEBHOOK_DELIVERY_EVENT_INDEX: &str = "ExporterWebhookDeliveryEvent";
+
+/// Removes all but the first delivery of each event to each URL, as could be queued by multiple
+/// replicas before deliveries were made unique
+const REMOVE_DUPLICATE_DELIVERIES: &str = "
+    DELETE later FROM ExporterWebhookDelivery AS later
+    INNER JOIN ExporterWebhookDelivery AS earlier
+        ON later.url = earlier.url
+        AND later.event_sequence = earlier.event_sequence
+        AND later.id > earlier.id
+";
+
+fn error_number(err: &DbErr) -> Option<u16> {
+    match err {
+        DbErr::Exec(RuntimeErr::SqlxError(SqlxError::Database(err)))
+        | DbErr::Query(RuntimeErr::SqlxError(SqlxError::Database(err))) => err
+            .try_downcast_ref::<SqlxMySqlError>()
+            .map(SqlxMySqlError::number),
+        _ => None,
+    }
+}
+
+/// Whether a statement failed because it would have duplicated a primary or unique key
+pub fn is_duplicate_key(err: &DbErr) -> bool {
+    error_number(err) == Some(DUPLICATE_ENTRY_ERROR)
+}
+
+async fn create_table_if_not_exists<E: EntityTrait>(
+    database: &DatabaseConnection,
+    entity: E,
+) -> Result<(), DbErr> {
+    let backend = database.get_database_backend();
+    let mut statement = Schema::new(backend).create_table_from_entity(entity);
+    statement.if_not_exists();
+    database.execute(backend.build(&statement)).await?;
+    Ok(())
+}
+
+/// Creates the unique index on the URL and event of each webhook delivery, unless it already
+/// exists, first removing any duplicate deliveries which would prevent its creation
+async fn create_webhook_delivery_index(database: &DatabaseConnection) -> Result<(), DbErr> {
+    let backend = database.get_database_backend();
+    let statement = backend.build(
+        &Index::create()
+            .name(WEBHOOK_DELIVERY_EVENT_INDEX)
+            .table(webhook_delivery::Entity)
+            .col(webhook_delivery::Column::Url)
+            .col(webhook_delivery::Column::EventSequence)
+            .unique()
+            .to_owned(),
+    );
+    match database.execute(statement.clone()).await {
+        Ok(_) => Ok(()),
+        Err(err) if error_number(&err) == Some(DUPLICATE_KEY_NAME_ERROR) => Ok(()),
+        Err(err) if is_duplicate_key(&err) => {
+            database
+                .execute(Statement::from_string(
+                    backend,
+                    REMOVE_DUPLICATE_DELIVERIES.to_string(),
+                ))
+                .await?;
+            database.execute(statement).await?;
+            Ok(())
+        }
+        Err(err) => Err(err),
+    }
+}
+
+/// Creates the tables owned by the exporter, alongside those of ISPyB, if they do not yet exist
+pub async fn setup_tables(database: &DatabaseConnection) -> Result<(), DbErr> {
+    create_table_if_not_exists(database, api_key::Entity).await?;
+    create_table_if_not_exists(database, audit_log::Entity).await?;
+    create_table_if_not_exists(database, event_lock::Entity).await?;
+    create_table_if_not_exists(database, event_log::Entity).await?;
+    create_table_if_not_exists(database, idempotency_key::Entity).await?;
+    create_table_if_not_exists(database, lease::Entity).await?;
+    create_table_if_not_exists(database, webhook_cursor::Entity).await?;
+    create_table_if_not_exists(database, webhook_delivery::Entity).await?;
+    create_webhook_delivery_index(database).await?;
+    let lock = event_lock::Entity::insert(event_lock::ActiveModel {
+        id: Set(event_lock::LOCK_ID),
+    })
+    .exec(database)
+    .await;
+    match lock {
+        Err(err) if !is_duplicate_key(&err) => Err(err),
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,351 @@
+use crate::{
+    api::ShipmentEvent,
+    broker::SharedEventBroker,
+    tables::{
+        event_log, is_duplicate_key, webhook_cursor,
+        webhook_delivery::{self, DeliveryStatus},
+    },
+};
+use async_graphql::futures_util::StreamExt;
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use sea_orm::{
+    sea_query::Expr, ActiveModelTrait, ColumnTrait, DatabaseConnection, DbErr, EntityTrait,
+    QueryFilter, QueryOrder, QuerySelect, Set,
+};
+use sha2::Sha256;
+use std::time::Duration;
+
+/// The header containing the hex encoded HMAC-SHA256 of the request body
+pub const SIGNATURE_HEADER: &str = "X-Exporter-Signature";
+
+/// The number of due deliveries attempted in each round
+const DELIVERY_BATCH_SIZE: u64 = 64;
+
+/// The number of events read from the event log at a time when queueing deliveries
+const ENQUEUE_BATCH_SIZE: u64 = 256;
+
+/// The interval at which the event log is checked for events recorded by other replicas
+const ENQUEUE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The longest delay between successive attempts of a delivery
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// The time allowed for each attempt to deliver a webhook
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The time for which a replica claims a delivery while attempting it, after which another may
+/// attempt it should the first have stopped without recording the outcome
+const DELIVERY_CLAIM_DURATION: Duration = Duration::from_secs(2 * 60);
+
+#[derive(Debug, Clone)]
+pub struct WebhookConfig {
+    /// The URLs to which each shipment event is POSTed
+    pub urls: Vec<String>,
+    /// The shared secret used to sign each payload
+    pub secret: String,
+    /// The number of attempts made before a delivery is marked as failed
+    pub max_attempts: u32,
+    /// The delay before the first retry, which doubles with each subsequent attempt
+    pub retry_delay: Duration,
+}
+
+/// Computes the value of the signature header for a payload
+fn sign(secret: &str, payload: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(payload.as_bytes());
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Finds the sequence number of the last event queued for delivery to the URL. A URL seen for the
+/// first time starts from its latest queued delivery, if any, or otherwise the latest event.
+async fn read_cursor(url: &str, database: &DatabaseConnection) -> Result<u64, DbErr> {
+    if let Some(cursor) = webhook_cursor::Entity::find_by_id(url.to_string())
+        .one(database)
+        .await?
+    {
+        return Ok(cursor.event_sequence);
+    }
+    let queued = webhook_delivery::Entity::find()
+        .select_only()
+        .column_as(webhook_delivery::Column::EventSequence.max(), "sequence")
+        .filter(webhook_delivery::Column::Url.eq(url))
+        .into_tuple::<Option<u64>>()
+        .one(database)
+        .await?
+        .flatten();
+    let event_sequence = match queued {
+        Some(sequence) => sequence,
+        None => event_log::Entity::find()
+            .select_only()
+            .column_as(event_log::Column::Sequence.max(), "sequence")
+            .into_tuple::<Option<u64>>()
+            .one(database)
+            .await?
+            .flatten()
+            .unwrap_or_default(),
+    };
+    let insert = webhook_cursor::Entity::insert(webhook_cursor::ActiveModel {
+        url: Set(url.to_string()),
+        event_sequence: Set(event_sequence),
+    })
+    .exec(database)
+    .await;
+    match insert {
+        Ok(_) => Ok(event_sequence),
+        // Another replica created the cursor first
+        Err(err) if is_duplicate_key(&err) => webhook_cursor::Entity::find_by_id(url.to_string())
+            .one(database)
+            .await?
+            .map(|cursor| cursor.event_sequence)
+            .ok_or(err),
+        Err(err) => Err(err),
+    }
+}
+
+/// Queues a delivery of the event to the URL, unless one has already been queued
+async fn queue_delivery(
+    url: &str,
+    event: &ShipmentEvent,
+    database: &DatabaseConnection,
+) -> Result<(), DbErr> {
+    let now = chrono::Utc::now();
+    let insert = webhook_delivery::Entity::insert(webhook_delivery::ActiveModel {
+        url: Set(url.to_string()),
+        event_sequence: Set(event.sequence),
+        payload: Set(serde_json::to_string(event).expect("Shipment events are serializable")),
+        status: Set(DeliveryStatus::Pending),
+        attempts: Set(0),
+        created_at: Set(now),
+        next_attempt_at: Set(now),
+        ..Default::default()
+    })
+    .exec(database)
+    .await;
+    match insert {
+        Err(err) if !is_duplicate_key(&err) => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Queues a delivery to each configured URL for every event recorded in the event log since the
+/// URL's cursor, then advances the cursor. Deliveries are unique to each URL and event, so
+/// replicas enqueueing concurrently queue each delivery only once.
+async fn enqueue_deliveries(
+    database: &DatabaseConnection,
+    config: &WebhookConfig,
+) -> Result<(), DbErr> {
+    for url in config.urls.iter() {
+        let mut cursor = read_cursor(url, database).await?;
+        loop {
+            let events = ShipmentEvent::replay(cursor, ENQUEUE_BATCH_SIZE, database).await?;
+            let Some(last_sequence) = events.last().map(|event| event.sequence) else {
+                break;
+            };
+            for event in events.iter() {
+                queue_delivery(url, event, database).await?;
+            }
+            webhook_cursor::Entity::update_many()
+                .col_expr(
+                    webhook_cursor::Column::EventSequence,
+                    Expr::value(last_sequence),
+                )
+                .filter(webhook_cursor::Column::Url.eq(url.as_str()))
+                .filter(webhook_cursor::Column::EventSequence.lt(last_sequence))
+                .exec(database)
+                .await?;
+            cursor = last_sequence;
+        }
+    }
+    Ok(())
+}
+
+async fn attempt_delivery(
+    client: &reqwest::Client,
+    config: &WebhookConfig,
+    delivery: &webhook_delivery::Model,
+) -> Result<(), reqwest::Error> {
+    client
+        .post(&delivery.url)
+        .header(CONTENT_TYPE, "application/json")
+        .header(SIGNATURE_HEADER, sign(&config.secret, &delivery.payload))
+        .body(delivery.payload.clone())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// The delay before retrying a delivery which has failed the given number of attempts, doubling
+/// with each attempt, or none if it should not be retried
+fn retry_delay(config: &WebhookConfig, attempts: u32) -> Option<Duration> {
+    if attempts >= config.max_attempts {
+        return None;
+    }
+    Some(
+        config
+            .retry_delay
+            .saturating_mul(2_u32.saturating_pow(attempts.saturating_sub(1)))
+            .min(MAX_RETRY_DELAY),
+    )
+}
+
+/// Claims a due delivery for this replica, such that no other replica attempts it concurrently,
+/// returning whether the claim succeeded
+async fn claim_delivery(
+    delivery: &webhook_delivery::Model,
+    database: &DatabaseConnection,
+) -> Result<bool, DbErr> {
+    let now = chrono::Utc::now();
+    let claim = webhook_delivery::Entity::update_many()
+        .col_expr(
+            webhook_delivery::Column::NextAttemptAt,
+            Expr::value(
+                now + chrono::Duration::from_std(DELIVERY_CLAIM_DURATION)
+                    .expect("Claim duration is bounded"),
+            ),
+        )
+        .filter(webhook_delivery::Column::Id.eq(delivery.id))
+        .filter(webhook_delivery::Column::Status.eq(DeliveryStatus::Pending))
+        .filter(webhook_delivery::Column::NextAttemptAt.lte(now))
+        .exec(database)
+        .await?;
+    Ok(claim.rows_affected == 1)
+}
+
+/// Attempts all due deliveries which can be claimed, rescheduling those which fail with
+/// exponential backoff
+async fn deliver_due(
+    database: &DatabaseConnection,
+    client: &reqwest::Client,
+    config: &WebhookConfig,
+) -> Result<(), DbErr> {
+    let due_deliveries = webhook_delivery::Entity::find()
+        .filter(webhook_delivery::Column::Status.eq(DeliveryStatus::Pending))
+        .filter(webhook_delivery::Column::NextAttemptAt.lte(chrono::Utc::now()))
+        .order_by_asc(webhook_delivery::Column::Id)
+        .limit(DELIVERY_BATCH_SIZE)
+        .all(database)
+        .await?;
+    for delivery in due_deliveries {
+        if !claim_delivery(&delivery, database).await? {
+            continue;
+        }
+        let result = attempt_delivery(client, config, &delivery).await;
+        let attempts = delivery.attempts + 1;
+        let mut update = webhook_delivery::ActiveModel::from(delivery);
+        update.attempts = Set(attempts);
+        match result {
+            Ok(()) => {
+                update.status = Set(DeliveryStatus::Delivered);
+                update.delivered_at = Set(Some(chrono::Utc::now()));
+                update.last_error = Set(None);
+            }
+            Err(err) => {
+                match retry_delay(config, attempts) {
+                    Some(delay) => {
+                        update.next_attempt_at = Set(chrono::Utc::now()
+                            + chrono::Duration::from_std(delay).expect("Retry delay is bounded"));
+                    }
+                    None => update.status = Set(DeliveryStatus::Failed),
+                }
+                update.last_error = Set(Some(err.to_string()));
+            }
+        }
+        update.update(database).await?;
+    }
+    Ok(())
+}
+
+/// Delivers shipment events to the configured webhooks as signed JSON. Deliveries are queued from
+/// the durable event log into the database, such that events recorded while the exporter was
+/// stopped, and deliveries pending when it stopped, are delivered when it restarts.
+pub async fn dispatch_webhooks(
+    database: DatabaseConnection,
+    event_broker: SharedEventBroker<ShipmentEvent>,
+    config: WebhookConfig,
+) {
+    let client = reqwest::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .build()
+        .expect("Could not build webhook HTTP client");
+    let enqueue = async {
+        let mut events = event_broker.subscribe();
+        let mut ticker = tokio::time::interval(ENQUEUE_INTERVAL);
+        loop {
+            // Events published by this replica are queued promptly, while those recorded by
+            // others, or missed by a lagging subscription, are found on the next tick
+            tokio::select! {
+                _ = ticker.tick() => {}
+                Some(_) = events.next() => {}
+            }
+            if let Err(err) = enqueue_deliveries(&database, &config).await {
+                tracing::error!("Could not queue webhook deliveries: {}", err);
+            }
+        }
+    };
+    let deliver = async {
+        let mut ticker = tokio::time::interval(Duration::from_secs(1));
+        loop {
+            ticker.tick().await;
+            if let Err(err) = deliver_due(&database, &client, &config).await {
+                tracing::error!("Could not deliver webhooks: {}", err);
+            }
+        }
+    };
+    tokio::join!(enqueue, deliver);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{retry_delay, sign, WebhookConfig, MAX_RETRY_DELAY};
+    use std::time::Duration;
+
+    fn config(max_attempts: u32) -> WebhookConfig {
+        WebhookConfig {
+            urls: vec!["https://example.com/hook".to_string()],
+            secret: "Jefe".to_string(),
+            max_attempts,
+            retry_delay: Duration::from_secs(10),
+        }
+    }
+
+    #[test]
+    fn signs_payloads_with_hmac_sha256() {
+        // Test case 2 of RFC 4231
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn doubles_the_delay_after_each_failed_attempt() {
+        let config = config(5);
+        assert_eq!(
+            (1..5)
+                .map(|attempts| retry_delay(&config, attempts))
+                .collect::<Vec<_>>(),
+            vec![
+                Some(Duration::from_secs(10)),
+                Some(Duration::from_secs(20)),
+                Some(Duration::from_secs(40)),
+                Some(Duration::from_secs(80)),
+            ]
+        );
+    }
+
+    #[test]
+    fn bounds_the_delay() {
+        assert_eq!(retry_delay(&config(100), 40), Some(MAX_RETRY_DELAY));
+    }
+
+    #[test]
+    fn fails_deliveries_after_the_last_attempt() {
+        let config = config(3);
+        assert!(retry_delay(&config, 2).is_some());
+        assert_eq!(retry_delay(&config, 3), None);
+        assert_eq!(retry_delay(&config, 4), None);
+    }
+}
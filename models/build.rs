@@ -1,140 +1,222 @@
+#[path = "discovery.rs"]
+mod discovery;
+
 const DATABASE_URL_ENV_VAR: &str = "DATABASE_URL";
 
+/// When set, the schema is read from the snapshot at this path rather than from a live database
+const SNAPSHOT_ENV_VAR: &str = "MODELS_SCHEMA_SNAPSHOT";
+
+/// The committed snapshot, read when the `offline` feature is enabled, as written by `regenerate-models`
+const DEFAULT_SNAPSHOT_PATH: &str = "schema.json";
+
 const MODELS_PATH: &str = "src/";
 
-const TABLES: &[&str] = &[
-    "Shipping",
-    "LabContact",
-    "Person",
-    "Proposal",
-    "Laboratory",
-    "Dewar",
-    "BLSession",
-    "BeamLineSetup",
-    "BeamCalendar",
-    "Detector",
-    "Container",
-    "ContainerRegistry",
-    "ContainerType",
-    "ExperimentType",
-    "Imager",
-    "ProcessingPipeline",
-    "ProcessingPipelineCategory",
-    "Schedule",
-    "Screen",
-    "BLSample",
-    "BLSubSample",
-    "Crystal",
-    "DiffractionPlan",
-    "ScreenComponentGroup",
-    "BLSampleImage",
-    "MotorPosition",
-    "Position",
-    "PurificationColumn",
-    "Protein",
-    "BLSampleImageScore",
-    "ContainerInspection",
-    "ComponentType",
-    "InspectionType",
-    "ScheduleComponent",
-    "ConcentrationType",
-];
+/// When set, the tables are read from the config at this path rather than the default
+const TABLES_CONFIG_ENV_VAR: &str = "MODELS_TABLES_CONFIG";
+
+/// The committed config listing the tables from which models are generated
+const DEFAULT_TABLES_CONFIG_PATH: &str = "tables.toml";
+
+/// The tables from which models are generated
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TablesConfig {
+    tables: Vec<String>,
+    /// Whether tables referenced by the foreign keys of those listed should also be generated
+    #[serde(default)]
+    include_dependencies: bool,
+}
+
+fn read_tables_config(path: &std::path::Path) -> TablesConfig {
+    let config = std::fs::read_to_string(path).unwrap_or_else(|_| {
+        panic!(
+            "Could not read the tables config at {}",
+            path.to_str().unwrap()
+        )
+    });
+    toml::from_str(&config).unwrap_or_else(|err| {
+        panic!(
+            "Could not parse the tables config at {}: {}",
+            path.to_str().unwrap(),
+            err
+        )
+    })
+}
+
+/// Selects the tables listed in the config, followed by the tables they reference through foreign
+/// keys if dependencies are to be included
+fn select_tables(
+    schema: sea_schema::mysql::def::Schema,
+    config: &TablesConfig,
+) -> Vec<sea_schema::mysql::def::TableDef> {
+    let mut selected = std::collections::BTreeSet::new();
+    let mut pending = config.tables.clone();
+    while let Some(name) = pending.pop() {
+        let table = schema
+            .tables
+            .iter()
+            .find(|table| table.info.name == name)
+            .unwrap_or_else(|| panic!("The table {} does not exist in the schema", name));
+        if !selected.insert(name) || !config.include_dependencies {
+            continue;
+        }
+        pending.extend(
+            table
+                .foreign_keys
+                .iter()
+                .map(|foreign_key| foreign_key.referenced_table.clone()),
+        );
+    }
+    schema
+        .tables
+        .into_iter()
+        .filter(|table| selected.contains(&table.info.name))
+        .collect()
+}
+
+/// Selects the snapshot to read the schema from, if any, preferring an explicitly supplied path
+fn snapshot_path() -> Option<std::path::PathBuf> {
+    std::env::var_os(SNAPSHOT_ENV_VAR)
+        .map(std::path::PathBuf::from)
+        .or_else(|| {
+            std::env::var_os("CARGO_FEATURE_OFFLINE")
+                .map(|_| std::path::PathBuf::from(DEFAULT_SNAPSHOT_PATH))
+        })
+}
+
+fn read_snapshot(path: &std::path::Path) -> sea_schema::mysql::def::Schema {
+    let snapshot = std::fs::read_to_string(path).unwrap_or_else(|_| {
+        panic!(
+            "Could not read the schema snapshot at {}. Run 'cargo run --package regenerate-models' against a live ISPyB to create it.",
+            path.to_str().unwrap()
+        )
+    });
+    serde_json::from_str(&snapshot).unwrap_or_else(|err| {
+        panic!(
+            "Could not parse the schema snapshot at {}: {}",
+            path.to_str().unwrap(),
+            err
+        )
+    })
+}
+
+async fn discover_live_schema() -> sea_schema::mysql::def::Schema {
+    let database_url = std::env::var(DATABASE_URL_ENV_VAR).unwrap_or_else(|_| {
+        panic!(
+            "The {} environment variable must point to an instance of ISPyB, or the {} environment variable or 'offline' feature must be used to read a schema snapshot.",
+            DATABASE_URL_ENV_VAR, SNAPSHOT_ENV_VAR
+        )
+    });
+    discovery::discover_schema(&database_url).await
+}
+
+/// Lists the columns of each table from which models are generated, such that the exporter can
+/// check them against the database it connects to
+fn compiled_schema_file(
+    tables: &[sea_schema::mysql::def::TableDef],
+) -> sea_orm_codegen::OutputFile {
+    let mut content = String::from(
+        "//! The columns of each table, as they were when the models were generated\n\npub const COMPILED_TABLES: &[(&str, &[&str])] = &[\n",
+    );
+    for table in tables {
+        let columns = table
+            .columns
+            .iter()
+            .map(|column| format!("{:?}", column.name))
+            .collect::<Vec<_>>()
+            .join(", ");
+        content.push_str(&format!("    ({:?}, &[{}]),\n", table.info.name, columns));
+    }
+    content.push_str("];\n");
+    sea_orm_codegen::OutputFile {
+        name: "compiled_schema.rs".to_string(),
+        content,
+    }
+}
 
 fn main() {
-    tokio::runtime::Builder::new_current_thread()
-        .enable_time()
-        .enable_io()
-        .build()
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=discovery.rs");
+    println!("cargo:rerun-if-env-changed={}", DATABASE_URL_ENV_VAR);
+    println!("cargo:rerun-if-env-changed={}", SNAPSHOT_ENV_VAR);
+    println!("cargo:rerun-if-env-changed={}", TABLES_CONFIG_ENV_VAR);
+
+    let tables_config_path = std::env::var_os(TABLES_CONFIG_ENV_VAR)
+        .map(std::path::PathBuf::from)
+        .unwrap_or_else(|| std::path::PathBuf::from(DEFAULT_TABLES_CONFIG_PATH));
+    println!(
+        "cargo:rerun-if-changed={}",
+        tables_config_path.to_str().unwrap()
+    );
+    let tables_config = read_tables_config(&tables_config_path);
+
+    let schema = match snapshot_path() {
+        Some(snapshot_path) => {
+            println!("cargo:rerun-if-changed={}", snapshot_path.to_str().unwrap());
+            read_snapshot(&snapshot_path)
+        }
+        None => tokio::runtime::Builder::new_current_thread()
+            .enable_time()
+            .enable_io()
+            .build()
+            .unwrap()
+            .block_on(discover_live_schema()),
+    };
+
+    let tables = select_tables(schema, &tables_config);
+    let compiled_schema = compiled_schema_file(&tables);
+    let table_statements = tables.into_iter().map(|table| table.write()).collect();
+
+    let writer_context = sea_orm_codegen::EntityWriterContext::new(
+        false,
+        sea_orm_codegen::WithSerde::Both,
+        true,
+        sea_orm_codegen::DateTimeCrate::Chrono,
+        None,
+        true,
+        false,
+        false,
+        vec![],
+        vec![],
+    );
+
+    let mut output = sea_orm_codegen::EntityTransformer::transform(table_statements)
         .unwrap()
-        .block_on(async {
-            let database_url_var = std::env::var(DATABASE_URL_ENV_VAR).unwrap_or_else(|_| {
-                panic!(
-                    "The {} environment variable must point to an instance of ISPyB.",
-                    DATABASE_URL_ENV_VAR
-                )
-            });
-            let database_url = url::Url::parse(&database_url_var).unwrap_or_else(|_| {
-                panic!(
-                    "The {} ({}) could not be parsed as a URL.",
-                    DATABASE_URL_ENV_VAR, database_url_var
-                )
-            });
+        .generate(&writer_context);
+    for file in output.files.iter_mut() {
+        if file.name == "lib.rs" {
+            file.content.push_str("\npub mod compiled_schema;\n");
+        }
+    }
+    output.files.push(compiled_schema);
 
-            let database_name = database_url
-                .path_segments()
-                .and_then(|mut segments| segments.next())
-                .unwrap_or_else(|| {
-                    panic!(
-                        "The {} ({}) does not contain a database name.",
-                        DATABASE_URL_ENV_VAR, database_url_var
-                    )
-                });
-
-            let connection = sqlx::Pool::<sqlx::MySql>::connect(database_url.as_str())
-                .await
-                .unwrap_or_else(|_| {
-                    panic!(
-                        "Could not connect to {} ({}).",
-                        DATABASE_URL_ENV_VAR, database_url_var
-                    )
-                });
-
-            let schema_discovery =
-                sea_schema::mysql::discovery::SchemaDiscovery::new(connection, database_name);
-            let schema = schema_discovery.discover().await;
-            let table_statements = schema
-                .tables
-                .into_iter()
-                .filter(|table| TABLES.contains(&table.info.name.as_str()))
-                .map(|table| table.write())
-                .collect();
-
-            let writer_context = sea_orm_codegen::EntityWriterContext::new(
-                false,
-                sea_orm_codegen::WithSerde::None,
-                true,
-                sea_orm_codegen::DateTimeCrate::Chrono,
-                None,
-                true,
-                false,
-                false,
-                vec![],
-                vec![],
-            );
-
-            let output = sea_orm_codegen::EntityTransformer::transform(table_statements)
-                .unwrap()
-                .generate(&writer_context);
-
-            let models_path = std::path::Path::new(MODELS_PATH);
-            std::fs::create_dir_all(models_path).unwrap_or_else(|_| {
-                panic!(
-                    "Could not create directory at {}",
-                    models_path.to_str().unwrap()
-                )
-            });
+    let models_path = std::path::Path::new(MODELS_PATH);
+    std::fs::create_dir_all(models_path).unwrap_or_else(|_| {
+        panic!(
+            "Could not create directory at {}",
+            models_path.to_str().unwrap()
+        )
+    });
 
-            output.files.iter().for_each(|output| {
-                let file_path = models_path.join(output.name.clone());
-                let mut file = std::fs::File::create(&file_path).unwrap_or_else(|_| {
-                    panic!("Could not create file at {}", file_path.to_str().unwrap())
-                });
-                std::io::Write::write_all(&mut file, output.content.as_bytes()).unwrap_or_else(
-                    |_| panic!("Could not write to file at {}", file_path.to_str().unwrap()),
-                );
-            });
+    output.files.iter().for_each(|output| {
+        let file_path = models_path.join(output.name.clone());
+        let mut file = std::fs::File::create(&file_path)
+            .unwrap_or_else(|_| panic!("Could not create file at {}", file_path.to_str().unwrap()));
+        std::io::Write::write_all(&mut file, output.content.as_bytes()).unwrap_or_else(|_| {
+            panic!("Could not write to file at {}", file_path.to_str().unwrap())
+        });
+    });
 
-            output.files.iter().for_each(|output| {
-                let file_path = models_path.join(output.name.clone());
-                let rustfmt_exit = std::process::Command::new("rustfmt")
-                    .arg(file_path.to_str().unwrap())
-                    .status()
-                    .unwrap_or_else(|_| {
-                        panic!("Could not run 'rustfmt' on {}", file_path.to_str().unwrap())
-                    });
-                if !rustfmt_exit.success() {
-                    panic!("Failed to format {}", file_path.to_str().unwrap())
-                };
+    output.files.iter().for_each(|output| {
+        let file_path = models_path.join(output.name.clone());
+        let rustfmt_exit = std::process::Command::new("rustfmt")
+            .arg(file_path.to_str().unwrap())
+            .status()
+            .unwrap_or_else(|_| {
+                panic!("Could not run 'rustfmt' on {}", file_path.to_str().unwrap())
             });
-        })
+        if !rustfmt_exit.success() {
+            panic!("Failed to format {}", file_path.to_str().unwrap())
+        };
+    });
 }
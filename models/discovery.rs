@@ -0,0 +1,26 @@
+//! Discovery of the ISPyB schema from a live database, shared by the models build script and the
+//! `regenerate-models` tool
+
+/// Discovers the schema of the database at `database_url`, which must include the database name
+pub async fn discover_schema(database_url: &str) -> sea_schema::mysql::def::Schema {
+    let parsed_url = url::Url::parse(database_url)
+        .unwrap_or_else(|_| panic!("The database URL ({}) could not be parsed.", database_url));
+
+    let database_name = parsed_url
+        .path_segments()
+        .and_then(|mut segments| segments.next())
+        .unwrap_or_else(|| {
+            panic!(
+                "The database URL ({}) does not contain a database name.",
+                database_url
+            )
+        });
+
+    let connection = sqlx::Pool::<sqlx::MySql>::connect(parsed_url.as_str())
+        .await
+        .unwrap_or_else(|_| panic!("Could not connect to {}.", database_url));
+
+    sea_schema::mysql::discovery::SchemaDiscovery::new(connection, database_name)
+        .discover()
+        .await
+}
@@ -0,0 +1,33 @@
+#[forbid(unsafe_code)]
+#[path = "../../models/discovery.rs"]
+mod discovery;
+
+use clap::Parser;
+use std::path::PathBuf;
+
+/// Refreshes the schema snapshot, from which the models are generated when building offline, by
+/// discovering the schema of a live ISPyB
+#[derive(Debug, Parser)]
+#[command(author, version, about, long_about = None)]
+struct Cli {
+    /// The URL of the ISPyB database, including the database name.
+    #[arg(long, env = "DATABASE_URL")]
+    database_url: String,
+    /// The file path to write the snapshot to.
+    #[arg(short, long, default_value = "models/schema.json")]
+    output: PathBuf,
+}
+
+#[tokio::main(flavor = "current_thread")]
+async fn main() {
+    let args = Cli::parse();
+    let schema = discovery::discover_schema(&args.database_url).await;
+    let snapshot = serde_json::to_string_pretty(&schema).unwrap();
+    std::fs::write(&args.output, snapshot + "\n")
+        .unwrap_or_else(|err| panic!("Could not write {}: {}", args.output.display(), err));
+    println!(
+        "Wrote {} tables to {}",
+        schema.tables.len(),
+        args.output.display()
+    );
+}